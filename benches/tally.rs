@@ -0,0 +1,142 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use futures_lite::future;
+use streamlette::{
+    Core, Decider, DeciderConfig, DiffMessage, LeaderSchedule, Message, MockClock, Proposal,
+    RoundRobin, Solicit, Timer, Vote,
+};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+/// How many ticks every instance runs for.
+const TICKS: u64 = 30;
+
+/// Player 0 of an instance, which gets fed everybody else's messages for a tick whenever it syncs, and keeps a copy of its core.
+struct Fed {
+    keys: Vec<Ed25519SK>,
+    ready: Arc<Mutex<Vec<DiffMessage>>>,
+    core: Arc<Mutex<Option<Core>>>,
+    clock: MockClock,
+}
+
+#[async_trait]
+impl DeciderConfig for Fed {
+    fn generate_proposal(&self) -> Bytes {
+        Bytes::from_static(b"bench")
+    }
+
+    fn verify_proposal(&self, _prop: &[u8]) -> bool {
+        true
+    }
+
+    async fn sync_core(&self, core: &mut Core) {
+        let ready = std::mem::take(&mut *self.ready.lock().unwrap());
+        for msg in ready {
+            let _ = core.apply_one_diff(msg);
+        }
+        *self.core.lock().unwrap() = Some(core.clone());
+        future::pending().await
+    }
+
+    fn vote_weights(&self) -> BTreeMap<Ed25519PK, u64> {
+        self.keys.iter().map(|sk| (sk.to_public(), 1)).collect()
+    }
+
+    fn seed(&self) -> u128 {
+        0
+    }
+
+    fn my_secret(&self) -> Ed25519SK {
+        self.keys[0]
+    }
+
+    fn leader_schedule(&self) -> Arc<dyn LeaderSchedule> {
+        Arc::new(RoundRobin::new(&self.vote_weights()))
+    }
+
+    fn timer(&self) -> Arc<dyn Timer> {
+        Arc::new(self.clock.clone())
+    }
+}
+
+/// Runs an instance with the given number of players for [TICKS] ticks, returning the core of player 0. Every third tick nothing happens, so that nothing is ever finalized, and all the ticks pile up.
+fn core_after_ticks(players: usize) -> Core {
+    let keys: Vec<Ed25519SK> = (0..players).map(|_| Ed25519SK::generate()).collect();
+    let config = Fed {
+        keys: keys.clone(),
+        ready: Default::default(),
+        core: Default::default(),
+        clock: MockClock::new(),
+    };
+    let schedule = config.leader_schedule();
+    let (ready, core) = (config.ready.clone(), config.core.clone());
+    let mut decider = Decider::new(config);
+    let sync = |decider: &mut Decider| {
+        future::block_on(decider.sync_state(Some(Duration::ZERO)));
+    };
+
+    let mut previous: Option<HashVal> = None;
+    for tick in 0..TICKS {
+        assert_eq!(decider.pre_tick().unwrap(), None);
+        if tick % 3 != 2 {
+            let leader = keys
+                .iter()
+                .find(|sk| sk.to_public() == schedule.leader(tick))
+                .unwrap();
+            let (hash, msg) = match previous {
+                None => {
+                    let prop = Proposal::new(0, tick, Bytes::from_static(b"bench"), *leader);
+                    (prop.chash(), DiffMessage::Proposal(prop))
+                }
+                Some(previous) => {
+                    let solicit = Solicit::new(0, tick, previous, *leader);
+                    (solicit.chash(), DiffMessage::Solicit(solicit))
+                }
+            };
+            let mut ready = ready.lock().unwrap();
+            ready.push(msg);
+            // player 0 votes by itself, if at all
+            ready.extend(
+                keys[1..]
+                    .iter()
+                    .map(|sk| DiffMessage::Vote(Vote::new(0, hash, *sk))),
+            );
+            previous = Some(hash);
+        }
+        sync(&mut decider);
+        assert_eq!(decider.post_tick().unwrap(), None);
+    }
+    sync(&mut decider);
+    let core = core.lock().unwrap().take().unwrap();
+    // everything got in, rather than being too far ahead of us
+    let votes = core
+        .get_diff(&Default::default())
+        .into_iter()
+        .filter(|msg| matches!(msg, DiffMessage::Vote(_)))
+        .count();
+    assert!(votes >= (TICKS as usize * 2 / 3) * (players - 1));
+    core
+}
+
+/// Looking for a finalized chain goes over the tips of the longest notarized chains and the notarization of everything on them, which the tally indices answer without scanning the votes. Its cost should barely grow with the number of votes.
+fn finality(c: &mut Criterion) {
+    let mut group = c.benchmark_group("finality_certificate");
+    group.sample_size(20);
+    for players in [10, 100, 300] {
+        let core = core_after_ticks(players);
+        assert!(core.finality_certificate().unwrap().is_none());
+        group.bench_with_input(BenchmarkId::from_parameter(players), &core, |b, core| {
+            b.iter(|| core.finality_certificate().unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, finality);
+criterion_main!(benches);
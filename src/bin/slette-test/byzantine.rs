@@ -0,0 +1,253 @@
+use std::{
+    collections::{btree_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use streamlette::{
+    Core, DeciderConfig, DiffMessage, Message, Proposal, Solicit, TickSchedule, Timer, Vote,
+};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+use crate::{network::Offer, MockConfig, SYNC_INTERVAL};
+
+/// How many sync rounds behind a [Behavior::ReplayingStale] player stays.
+const STALENESS: usize = 100;
+
+/// A way for a player to misbehave.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Behavior {
+    /// Shows half of the other players a different proposal or solicit for every tick it leads.
+    EquivocatingLeader,
+    /// Never shows anybody its votes.
+    WithholdingVotes,
+    /// Votes for every proposal and solicit it sees, not just those extending a longest notarized chain.
+    VotingForEverything,
+    /// Only ever shows what it had a while ago, over and over again.
+    ReplayingStale,
+    /// For every tick it leads, shows a solicit extending something other than the tip of a longest notarized chain instead.
+    ForgingSolicits,
+    /// Behaves honestly, but only shares with some of the other players.
+    SelectiveSharing,
+}
+
+impl Behavior {
+    pub const ALL: [Behavior; 6] = [
+        Behavior::EquivocatingLeader,
+        Behavior::WithholdingVotes,
+        Behavior::VotingForEverything,
+        Behavior::ReplayingStale,
+        Behavior::ForgingSolicits,
+        Behavior::SelectiveSharing,
+    ];
+}
+
+/// A Byzantine player. It runs the protocol like everybody else, so that it knows what's going on and has honest messages to work with, but only ever shows others what its [Behavior] dictates, through the public API of [Core].
+pub struct ByzantineConfig {
+    inner: MockConfig,
+    behavior: Behavior,
+    /// Who we share with, if we're selective.
+    audience: BTreeSet<usize>,
+    /// What we had in the past few sync rounds, oldest first.
+    history: Mutex<VecDeque<Vec<DiffMessage>>>,
+    /// What we voted for, if we vote for everything.
+    voted: Mutex<HashSet<HashVal>>,
+    /// The second proposal or solicit we made up for each tick we lead, if we equivocate.
+    twins: Mutex<BTreeMap<u64, DiffMessage>>,
+}
+
+impl ByzantineConfig {
+    pub fn new(inner: MockConfig, behavior: Behavior) -> Self {
+        let audience = (0..inner.participants.len())
+            .filter(|i| *i != inner.index && inner.rng.u64().is_multiple_of(2))
+            .collect();
+        Self {
+            inner,
+            behavior,
+            audience,
+            history: Default::default(),
+            voted: Default::default(),
+            twins: Default::default(),
+        }
+    }
+
+    fn my_public(&self) -> Ed25519PK {
+        self.inner.my_secret().to_public()
+    }
+
+    fn others(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.inner.participants.len()).filter(move |i| *i != self.inner.index)
+    }
+
+    /// Decides what to show the others, given our core.
+    fn offer(&self, core: &mut Core) -> Offer {
+        let me = self.my_public();
+        let nonce = self.inner.seed();
+        let sk = self.inner.my_secret();
+        let everything = core.get_diff(&HashMap::new());
+        match self.behavior {
+            Behavior::EquivocatingLeader => {
+                // odd players see a twin of everything we lead with
+                let mut twins = self.twins.lock().unwrap();
+                let twins = everything
+                    .iter()
+                    .map(|msg| {
+                        match msg {
+                            DiffMessage::Proposal(p) if p.source == me => {
+                                &*twins.entry(p.tick).or_insert_with(|| {
+                                    DiffMessage::Proposal(Proposal::new(
+                                        nonce,
+                                        p.tick,
+                                        self.inner.generate_proposal(),
+                                        sk,
+                                    ))
+                                })
+                            }
+                            DiffMessage::Solicit(s) if s.source == me => {
+                                match twins.entry(s.tick) {
+                                    Entry::Occupied(twin) => &*twin.into_mut(),
+                                    Entry::Vacant(vacant) => {
+                                        match twin_solicit(&everything, s, nonce, sk) {
+                                            Some(twin) => &*vacant.insert(twin),
+                                            None => return msg.clone(),
+                                        }
+                                    }
+                                }
+                            }
+                            _ => return msg.clone(),
+                        }
+                        .clone()
+                    })
+                    .collect::<Vec<_>>();
+                Offer::PerReceiver(
+                    self.others()
+                        .map(|i| {
+                            let msgs = if i % 2 == 0 { &everything } else { &twins };
+                            (i, msgs.clone())
+                        })
+                        .collect(),
+                )
+            }
+            Behavior::WithholdingVotes => Offer::Messages(
+                everything
+                    .into_iter()
+                    .filter(|msg| !matches!(msg, DiffMessage::Vote(v) if v.source == me))
+                    .collect(),
+            ),
+            Behavior::VotingForEverything => {
+                let mut voted = self.voted.lock().unwrap();
+                for msg in everything {
+                    let target = match msg {
+                        DiffMessage::Proposal(p) => p.chash(),
+                        DiffMessage::Solicit(s) => s.chash(),
+                        DiffMessage::Vote(_) => continue,
+                    };
+                    if voted.insert(target) {
+                        let _ =
+                            core.apply_one_diff(DiffMessage::Vote(Vote::new(nonce, target, sk)));
+                    }
+                }
+                Offer::Core(Box::new(core.clone()))
+            }
+            Behavior::ReplayingStale => {
+                let mut history = self.history.lock().unwrap();
+                history.push_back(everything);
+                if history.len() > STALENESS {
+                    history.pop_front();
+                }
+                Offer::Messages(history[0].clone())
+            }
+            Behavior::ForgingSolicits => {
+                let forged = everything
+                    .iter()
+                    .filter_map(|msg| {
+                        let (tick, previous) = match msg {
+                            DiffMessage::Proposal(p) if p.source == me => (p.tick, None),
+                            DiffMessage::Solicit(s) if s.source == me => (s.tick, Some(s.previous)),
+                            _ => return Some(msg.clone()),
+                        };
+                        // anything older that isn't what an honest leader would extend
+                        let target = everything.iter().find_map(|other| match other {
+                            DiffMessage::Proposal(p) if p.tick < tick => Some(p.chash()),
+                            DiffMessage::Solicit(s) if s.tick < tick => Some(s.chash()),
+                            _ => None,
+                        })?;
+                        (Some(target) != previous)
+                            .then(|| DiffMessage::Solicit(Solicit::new(nonce, tick, target, sk)))
+                    })
+                    .collect();
+                Offer::Messages(forged)
+            }
+            Behavior::SelectiveSharing => Offer::PerReceiver(
+                self.audience
+                    .iter()
+                    .map(|i| (*i, everything.clone()))
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// A solicit for the same tick as the given one, but extending something else in `everything`, if there is anything else it can extend.
+fn twin_solicit(
+    everything: &[DiffMessage],
+    solicit: &Solicit,
+    nonce: u128,
+    sk: Ed25519SK,
+) -> Option<DiffMessage> {
+    let previous = everything.iter().find_map(|other| {
+        let (tick, hash) = match other {
+            DiffMessage::Proposal(p) => (p.tick, p.chash()),
+            DiffMessage::Solicit(s) => (s.tick, s.chash()),
+            DiffMessage::Vote(_) => return None,
+        };
+        (tick < solicit.tick && hash != solicit.previous).then_some(hash)
+    })?;
+    Some(DiffMessage::Solicit(Solicit::new(
+        nonce,
+        solicit.tick,
+        previous,
+        sk,
+    )))
+}
+
+#[async_trait]
+impl DeciderConfig for ByzantineConfig {
+    fn generate_proposal(&self) -> Bytes {
+        self.inner.generate_proposal()
+    }
+
+    fn verify_proposal(&self, prop: &[u8]) -> bool {
+        self.inner.verify_proposal(prop)
+    }
+
+    async fn sync_core(&self, core: &mut Core) {
+        loop {
+            self.inner.receive(core);
+            let offer = self.offer(core);
+            self.inner.send(offer);
+            self.inner.clock.sleep(SYNC_INTERVAL).await;
+        }
+    }
+
+    fn vote_weights(&self) -> BTreeMap<Ed25519PK, u64> {
+        self.inner.vote_weights()
+    }
+
+    fn seed(&self) -> u128 {
+        self.inner.seed()
+    }
+
+    fn my_secret(&self) -> Ed25519SK {
+        self.inner.my_secret()
+    }
+
+    fn tick_schedule(&self) -> Box<dyn TickSchedule> {
+        self.inner.tick_schedule()
+    }
+
+    fn timer(&self) -> Arc<dyn Timer> {
+        self.inner.timer()
+    }
+}
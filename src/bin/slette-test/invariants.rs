@@ -0,0 +1,421 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    time::Duration,
+};
+
+use bytes::Bytes;
+use streamlette::{Core, DiffMessage, Fatal, FinalityCertificate, Message};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+/// Something that must never happen, as long as fewer than a third of the votes are Byzantine.
+#[derive(Clone, Debug)]
+pub enum Violation {
+    /// Two honest players decided differently.
+    Disagreement {
+        first: (usize, Bytes),
+        second: (usize, Bytes),
+    },
+    /// An honest player decided something that no player ever proposed, or that isn't valid.
+    Invalid { player: usize, decision: Bytes },
+    /// An honest player gave up with a fatal error.
+    Fatal { player: usize, error: Fatal },
+    /// An honest player decided without a finality certificate that a light client would accept.
+    Unproven { player: usize, decision: Bytes },
+    /// An honest player voted for something that didn't extend a longest notarized chain it knew of.
+    OffChainVote { player: usize, target: HashVal },
+    /// An honest player voted for two different messages for the same tick.
+    DoubleVote { player: usize, tick: u64 },
+    /// A message was notarized even though it conflicts with a chain that was already final at its height.
+    Inconsistent {
+        final_msg: HashVal,
+        conflicting: HashVal,
+    },
+    /// Some honest players still hadn't decided long after the network stabilized.
+    Stalled { undecided: BTreeSet<usize> },
+}
+
+impl Violation {
+    /// Whether an honest player broke a rule of the protocol, as checked against hard-coded default parameters, or an outcome showed that somebody did. This is not a safety violation by itself, since the honest players may still agree.
+    #[cfg_attr(not(all(test, feature = "mutation-testing")), allow(dead_code))]
+    pub fn is_nonconformance(&self) -> bool {
+        !matches!(self, Violation::Stalled { .. })
+    }
+
+    /// The honest players whose doings show the violation, or nobody in particular if it shows in what everybody sent.
+    pub fn players(&self) -> BTreeSet<usize> {
+        match self {
+            Violation::Disagreement { first, second } => [first.0, second.0].into(),
+            Violation::Invalid { player, .. }
+            | Violation::Fatal { player, .. }
+            | Violation::Unproven { player, .. }
+            | Violation::OffChainVote { player, .. }
+            | Violation::DoubleVote { player, .. } => [*player].into(),
+            Violation::Inconsistent { .. } => BTreeSet::new(),
+            Violation::Stalled { undecided } => undecided.clone(),
+        }
+    }
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::Disagreement { first, second } => write!(
+                f,
+                "disagreement: {} decided {:?}, but {} decided {:?}",
+                first.0, first.1, second.0, second.1
+            ),
+            Violation::Invalid { player, decision } => {
+                write!(f, "invalid decision by {}: {:?}", player, decision)
+            }
+            Violation::Fatal { player, error } => write!(f, "fatal error in {}: {}", player, error),
+            Violation::Unproven { player, decision } => write!(
+                f,
+                "{} decided {:?} without a valid finality certificate",
+                player, decision
+            ),
+            Violation::OffChainVote { player, target } => write!(
+                f,
+                "{} voted for {}, which doesn't extend a longest notarized chain",
+                player, target
+            ),
+            Violation::DoubleVote { player, tick } => {
+                write!(f, "{} voted twice for tick {}", player, tick)
+            }
+            Violation::Inconsistent {
+                final_msg,
+                conflicting,
+            } => write!(
+                f,
+                "{} was notarized, conflicting with final {}",
+                conflicting, final_msg
+            ),
+            Violation::Stalled { undecided } => {
+                write!(f, "no liveness: {:?} never decided", undecided)
+            }
+        }
+    }
+}
+
+/// Something that happened during a run, in the trace reported along with a [Violation].
+#[derive(Clone, Debug)]
+enum Event {
+    Proposed(Bytes),
+    Decided(Bytes),
+    Failed(String),
+}
+
+impl Display for Event {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Event::Proposed(body) => write!(f, "proposed {:?}", body),
+            Event::Decided(decision) => write!(f, "decided {:?}", decision),
+            Event::Failed(error) => write!(f, "failed: {}", error),
+        }
+    }
+}
+
+/// Keeps track of a simulated run, checking that the honest players reach agreement on a valid decision, and that they all do so within a bounded time after global stabilization time (GST).
+#[derive(Clone)]
+pub struct Invariants {
+    seed: u128,
+    honest: BTreeSet<usize>,
+    weights: Vec<(Ed25519PK, u64)>,
+    nonce: u128,
+    gst: Duration,
+    liveness_bound: Duration,
+    verify: fn(&[u8]) -> bool,
+
+    proposed: BTreeSet<Bytes>,
+    decisions: BTreeMap<usize, Bytes>,
+    /// The votes of each honest player that were already checked by [Invariants::check_votes].
+    checked_votes: BTreeMap<usize, HashSet<HashVal>>,
+    violation: Option<Violation>,
+    trace: Vec<(Duration, usize, Event)>,
+}
+
+impl Invariants {
+    /// Creates a checker for the run with the given seed, where every honest player must decide within `liveness_bound` of `gst`, and decisions must pass `verify`. Players are numbered by their position in `weights`, and play the instance with the given nonce.
+    pub fn new(
+        seed: u128,
+        honest: BTreeSet<usize>,
+        weights: Vec<(Ed25519PK, u64)>,
+        nonce: u128,
+        gst: Duration,
+        liveness_bound: Duration,
+        verify: fn(&[u8]) -> bool,
+    ) -> Self {
+        Self {
+            seed,
+            honest,
+            weights,
+            nonce,
+            gst,
+            liveness_bound,
+            verify,
+            proposed: BTreeSet::new(),
+            decisions: BTreeMap::new(),
+            checked_votes: BTreeMap::new(),
+            violation: None,
+            trace: vec![],
+        }
+    }
+
+    /// Records a proposal body coming out of some player's `generate_proposal`. Byzantine players only misbehave at the protocol level, so theirs count too.
+    pub fn record_proposal(&mut self, at: Duration, player: usize, body: Bytes) {
+        self.trace.push((at, player, Event::Proposed(body.clone())));
+        self.proposed.insert(body);
+    }
+
+    /// Records the outcome of an honest player's `tick_to_end`, along with the finality certificate it had right then, checking agreement and validity right away.
+    pub fn record_outcome(
+        &mut self,
+        at: Duration,
+        player: usize,
+        outcome: Result<Bytes, Fatal>,
+        certificate: Option<FinalityCertificate>,
+    ) {
+        let decision = match outcome {
+            Ok(decision) => decision,
+            Err(error) => {
+                self.trace
+                    .push((at, player, Event::Failed(error.to_string())));
+                self.violate(Violation::Fatal { player, error });
+                return;
+            }
+        };
+        self.trace
+            .push((at, player, Event::Decided(decision.clone())));
+        if !self.proposed.contains(&decision) || !(self.verify)(&decision) {
+            self.violate(Violation::Invalid {
+                player,
+                decision: decision.clone(),
+            });
+        }
+        // the real protocol parameters, whatever the players themselves were running
+        let weights = self.weights.iter().copied().collect();
+        if !certificate
+            .is_some_and(|cert| cert.proposal.body == decision && cert.verify(&weights, self.nonce))
+        {
+            self.violate(Violation::Unproven {
+                player,
+                decision: decision.clone(),
+            });
+        }
+        if let Some((&other, other_decision)) = self
+            .decisions
+            .iter()
+            .find(|(_, other_decision)| **other_decision != decision)
+        {
+            self.violate(Violation::Disagreement {
+                first: (other, other_decision.clone()),
+                second: (player, decision.clone()),
+            });
+        }
+        self.decisions.insert(player, decision);
+    }
+
+    /// Checks that time hasn't run out for any honest player to decide, returning whether every honest player has.
+    pub fn check(&mut self, now: Duration) -> bool {
+        let undecided: BTreeSet<usize> = self
+            .honest
+            .iter()
+            .filter(|player| !self.decisions.contains_key(player))
+            .copied()
+            .collect();
+        if !undecided.is_empty() && now > self.gst + self.liveness_bound {
+            self.violate(Violation::Stalled { undecided });
+        }
+        self.violation.is_some() || self.decisions.len() == self.honest.len()
+    }
+
+    /// Checks the votes that an honest player cast since the last call, given everything it knows right after casting them. Each must be for a proposal if nothing is notarized yet, or else for a solicit extending the tip of a longest notarized chain, as if the votes themselves weren't there.
+    pub fn check_votes(&mut self, player: usize, view: &Core) {
+        if !self.honest.contains(&player) {
+            return;
+        }
+        let me = self.weights[player].0;
+        let weights: HashMap<Ed25519PK, u64> = self.weights.iter().copied().collect();
+        let checked = self.checked_votes.entry(player).or_default();
+        let mut new_votes = vec![];
+        let mut msgs = HashMap::new();
+        let mut tallies: HashMap<HashVal, u128> = HashMap::new();
+        for msg in view.get_diff(&HashMap::new()) {
+            match msg {
+                DiffMessage::Vote(v) if v.source == me && checked.insert(v.voting_for) => {
+                    new_votes.push(v.voting_for)
+                }
+                DiffMessage::Vote(v) => {
+                    *tallies.entry(v.voting_for).or_default() +=
+                        weights.get(&v.source).copied().unwrap_or_default() as u128
+                }
+                DiffMessage::Proposal(p) => {
+                    msgs.insert(p.chash(), None);
+                }
+                DiffMessage::Solicit(s) => {
+                    msgs.insert(s.chash(), Some(s.previous));
+                }
+            }
+        }
+        if new_votes.is_empty() {
+            return;
+        }
+        let height = |mut h: HashVal| {
+            let mut height = 0;
+            while let Some(Some(prev)) = msgs.get(&h) {
+                h = *prev;
+                height += 1;
+            }
+            height
+        };
+        let notarized: Vec<HashVal> = tallies
+            .iter()
+            .filter(|(h, tally)| msgs.contains_key(h) && self.is_quorum(**tally))
+            .map(|(h, _)| *h)
+            .collect();
+        let longest = notarized.iter().map(|h| height(*h)).max();
+        for target in new_votes {
+            let ok = match (longest, msgs.get(&target)) {
+                (None, Some(None)) => true,
+                (Some(longest), Some(Some(prev))) => {
+                    notarized.contains(prev) && height(*prev) == longest
+                }
+                _ => false,
+            };
+            if !ok {
+                self.violate(Violation::OffChainVote { player, target });
+            }
+        }
+    }
+
+    /// Checks everything that was ever sent, at the end of a run. Honest players must never vote twice for the same tick, and, as in Streamlet's consistency proof, once three notarized messages with consecutive ticks make the chain up to the middle one final, every notarized message at least as high must build on it. This holds no matter which players saw what, so it catches forks that no honest player happened to decide on.
+    pub fn check_messages(&mut self, msgs: impl IntoIterator<Item = DiffMessage>) {
+        let mut parents: HashMap<HashVal, (u64, Option<HashVal>)> = HashMap::new();
+        let mut votes: HashSet<(HashVal, Ed25519PK)> = HashSet::new();
+        for msg in msgs {
+            match msg {
+                DiffMessage::Proposal(p) => {
+                    parents.insert(p.chash(), (p.tick, None));
+                }
+                DiffMessage::Solicit(s) => {
+                    parents.insert(s.chash(), (s.tick, Some(s.previous)));
+                }
+                DiffMessage::Vote(v) => {
+                    votes.insert((v.voting_for, v.source));
+                }
+            }
+        }
+        let weights: HashMap<Ed25519PK, u64> = self.weights.iter().copied().collect();
+        let mut tallies: HashMap<HashVal, u128> = HashMap::new();
+        let mut voted_at: HashMap<(Ed25519PK, u64), HashVal> = HashMap::new();
+        for (target, source) in &votes {
+            *tallies.entry(*target).or_default() +=
+                weights.get(source).copied().unwrap_or_default() as u128;
+            let Some((tick, _)) = parents.get(target) else {
+                continue;
+            };
+            if let Some(player) = self.honest_player(*source) {
+                if voted_at
+                    .insert((*source, *tick), *target)
+                    .is_some_and(|t| t != *target)
+                {
+                    self.violate(Violation::DoubleVote {
+                        player,
+                        tick: *tick,
+                    });
+                }
+            }
+        }
+        let notarized = |h: &HashVal| tallies.get(h).is_some_and(|t| self.is_quorum(*t));
+        // the chain from each message back to its proposal, newest first
+        let chain = |mut h: HashVal| {
+            let mut chain = vec![h];
+            while let Some((_, Some(prev))) = parents.get(&h) {
+                h = *prev;
+                chain.push(h);
+            }
+            chain
+        };
+        let chains: Vec<Vec<HashVal>> = parents
+            .keys()
+            .filter(|h| notarized(h))
+            .map(|h| chain(*h))
+            // skip anything we can't trace back to a proposal
+            .filter(|c| {
+                parents
+                    .get(c.last().unwrap())
+                    .is_some_and(|(_, prev)| prev.is_none())
+            })
+            .collect();
+        let mut inconsistent = vec![];
+        for final_chain in chains.iter() {
+            let consecutive = final_chain.len() >= 3
+                && final_chain[..3].iter().all(&notarized)
+                && final_chain[..3]
+                    .windows(2)
+                    .all(|w| parents[&w[0]].0 == parents[&w[1]].0 + 1);
+            if !consecutive {
+                continue;
+            }
+            let height = final_chain.len() - 1;
+            let final_msg = final_chain[1];
+            for other in chains.iter().filter(|c| c.len() >= height) {
+                if other[other.len() - height] != final_msg {
+                    inconsistent.push(Violation::Inconsistent {
+                        final_msg,
+                        conflicting: other[0],
+                    });
+                }
+            }
+        }
+        for violation in inconsistent {
+            self.violate(violation);
+        }
+    }
+
+    /// Whether the given vote weight is more than 2/3 of the total, as in the real protocol.
+    fn is_quorum(&self, weight: u128) -> bool {
+        let total: u128 = self.weights.iter().map(|(_, w)| *w as u128).sum();
+        weight * 3 > total * 2
+    }
+
+    fn honest_player(&self, key: Ed25519PK) -> Option<usize> {
+        self.honest
+            .iter()
+            .copied()
+            .find(|i| self.weights[*i].0 == key)
+    }
+
+    /// Returns what each honest player decided, if it did.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn decisions(&self) -> &BTreeMap<usize, Bytes> {
+        &self.decisions
+    }
+
+    /// Returns the first violation found, if any.
+    pub fn violation(&self) -> Option<&Violation> {
+        self.violation.as_ref()
+    }
+
+    /// Panics with the seed and the trace of the run if any invariant was violated.
+    pub fn assert_upheld(&self) {
+        if let Some(violation) = &self.violation {
+            panic!("{}", self.report(violation));
+        }
+    }
+
+    fn violate(&mut self, violation: Violation) {
+        self.violation.get_or_insert(violation);
+    }
+
+    fn report(&self, violation: &Violation) -> String {
+        let mut report = format!(
+            "INVARIANT VIOLATED with seed {}: {}\nhonest players: {:?}, GST at {:?}\n",
+            self.seed, violation, self.honest, self.gst
+        );
+        for (at, player, event) in &self.trace {
+            report += &format!("[{:>12?}] {} {}\n", at, player, event);
+        }
+        report
+    }
+}
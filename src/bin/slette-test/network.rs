@@ -0,0 +1,227 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::Display,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use streamlette::{Core, DiffLimit, DiffMessage};
+
+use crate::fakerng::FakeRng;
+
+/// What a player sends the others.
+pub enum Offer {
+    /// Everything in the player's core, to everybody.
+    Core(Box<Core>),
+    /// The same messages, in causal order, to everybody.
+    Messages(Vec<DiffMessage>),
+    /// Different messages, in causal order, for each receiver. Receivers missing from the map get nothing.
+    PerReceiver(BTreeMap<usize, Vec<DiffMessage>>),
+}
+
+/// How much longer than the base latency of its link a packet can take after GST.
+const STABLE_JITTER: Duration = Duration::from_millis(100);
+
+/// The most a link can take, at the very least.
+const MAX_BASE_LATENCY: Duration = Duration::from_millis(200);
+
+/// The most a link can take on top of its base latency, before GST.
+const MAX_JITTER: Duration = Duration::from_secs(3);
+
+/// How packets fare between two players.
+struct Link {
+    /// How long every packet takes at least.
+    base: Duration,
+    /// How much longer than `base` a packet can take, uniformly at random. After GST, this is capped at [STABLE_JITTER].
+    jitter: Duration,
+    /// The chance that a packet gets lost before GST, in thousandths.
+    loss: u64,
+    /// The chance that a packet gets delivered twice, in thousandths.
+    duplication: u64,
+}
+
+/// A stretch of time during which the players on one side can't reach the players on the other.
+pub struct Partition {
+    pub start: Duration,
+    pub end: Duration,
+    pub side: BTreeSet<usize>,
+}
+
+impl Partition {
+    fn separates(&self, at: Duration, a: usize, b: usize) -> bool {
+        self.start <= at && at < self.end && self.side.contains(&a) != self.side.contains(&b)
+    }
+}
+
+/// Which part of an offer a packet carries. Votes travel apart from what they vote for, so that they can overtake it or fall far behind.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Votes,
+    Rest,
+}
+
+struct Packet {
+    from: usize,
+    offer: Arc<Offer>,
+    part: Part,
+}
+
+/// A simulated network between the players, partially synchronous around a global stabilization time (GST).
+///
+/// Every link has its own latency distribution, loss rate and duplication rate, and packets overtake each other whenever their latencies say so. Before GST, packets get lost, and the players are split by a schedule of partitions. At GST, the partitions are over, nothing gets lost anymore, and every packet, including those sent before, arrives within the base latency of its link plus [STABLE_JITTER].
+pub struct Network {
+    rng: FakeRng,
+    gst: Duration,
+    /// The link between every sender and every receiver.
+    links: Vec<Vec<Link>>,
+    partitions: Vec<Partition>,
+    /// The packets on their way to each player, by arrival time and then by when they were sent.
+    in_flight: Mutex<Vec<BTreeMap<(Duration, u64), Packet>>>,
+    /// How many packets were ever sent.
+    sent: Mutex<u64>,
+    /// What each player offered last.
+    latest: Mutex<BTreeMap<usize, Arc<Offer>>>,
+}
+
+impl Network {
+    /// Creates a network of the given number of players, with random links and a random schedule of partitions before GST.
+    pub fn new(rng: &FakeRng, players: usize, gst: Duration) -> Self {
+        let random =
+            |max: Duration| Duration::from_millis(rng.u64() % (max.as_millis() as u64 + 1));
+        let links = (0..players)
+            .map(|_| {
+                (0..players)
+                    .map(|_| Link {
+                        base: random(MAX_BASE_LATENCY),
+                        jitter: random(MAX_JITTER),
+                        loss: rng.u64() % 300,
+                        duplication: rng.u64() % 100,
+                    })
+                    .collect()
+            })
+            .collect();
+        let partitions = (0..rng.u64() % 4)
+            .map(|_| {
+                let start = random(gst);
+                Partition {
+                    start,
+                    end: (start + random(Duration::from_secs(10))).min(gst),
+                    side: (0..players)
+                        .filter(|_| rng.u64().is_multiple_of(2))
+                        .collect(),
+                }
+            })
+            .collect();
+        Self {
+            rng: rng.clone(),
+            gst,
+            links,
+            partitions,
+            in_flight: Mutex::new((0..players).map(|_| BTreeMap::new()).collect()),
+            sent: Mutex::new(0),
+            latest: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Sends the given offer from one player to another at the given time, as two packets: one with the votes, one with everything else.
+    pub fn send(&self, from: usize, to: usize, at: Duration, offer: Offer) {
+        let offer = Arc::new(offer);
+        self.latest.lock().unwrap().insert(from, offer.clone());
+        if self.partitions.iter().any(|p| p.separates(at, from, to)) {
+            return;
+        }
+        let link = &self.links[from][to];
+        for part in [Part::Rest, Part::Votes] {
+            let chance = |thousandths| self.rng.u64() % 1000 < thousandths;
+            if at < self.gst && chance(link.loss) {
+                continue;
+            }
+            let copies = if chance(link.duplication) { 2 } else { 1 };
+            for _ in 0..copies {
+                let jitter =
+                    Duration::from_nanos(self.rng.u64() % (link.jitter.as_nanos() as u64 + 1));
+                let arrival =
+                    (at + link.base + jitter).min(at.max(self.gst) + link.base + STABLE_JITTER);
+                let mut sent = self.sent.lock().unwrap();
+                *sent += 1;
+                self.in_flight.lock().unwrap()[to].insert(
+                    (arrival, *sent),
+                    Packet {
+                        from,
+                        offer: offer.clone(),
+                        part,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Receives every packet that arrived at the given player by the given time, returning the messages in them that `core` lacks, roughly, along with who sent each.
+    pub fn receive(&self, to: usize, at: Duration, core: &Core) -> Vec<(usize, DiffMessage)> {
+        let arrived = {
+            let mut in_flight = self.in_flight.lock().unwrap();
+            let later = in_flight[to].split_off(&(at + Duration::from_nanos(1), 0));
+            std::mem::replace(&mut in_flight[to], later)
+        };
+        arrived
+            .into_values()
+            .flat_map(|packet| {
+                messages_in(&packet.offer, to, core)
+                    .into_iter()
+                    .filter(move |msg| {
+                        matches!(msg, DiffMessage::Vote(_)) == (packet.part == Part::Votes)
+                    })
+                    .map(move |msg| (packet.from, msg))
+            })
+            .collect()
+    }
+
+    /// Everything anybody offered last, to anybody.
+    pub fn everything(&self) -> Vec<DiffMessage> {
+        self.latest
+            .lock()
+            .unwrap()
+            .values()
+            .flat_map(|offer| match offer.as_ref() {
+                Offer::Core(core) => core.get_diff(&HashMap::new()),
+                Offer::Messages(msgs) => msgs.clone(),
+                Offer::PerReceiver(per_receiver) => {
+                    per_receiver.values().flatten().cloned().collect()
+                }
+            })
+            .collect()
+    }
+}
+
+impl Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "GST at {:?}", self.gst)?;
+        for partition in &self.partitions {
+            write!(
+                f,
+                ", {:?} cut off from {:?} to {:?}",
+                partition.side, partition.start, partition.end
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// The messages in the offer for `to` that `core` lacks, roughly.
+fn messages_in(offer: &Offer, to: usize, core: &Core) -> Vec<DiffMessage> {
+    match offer {
+        Offer::Core(theirs) => {
+            let ticks = theirs.mismatched_ticks(&core.compact_summary(4));
+            theirs
+                .get_diff_for_ticks(
+                    &ticks,
+                    &core.summary_for_ticks(&ticks),
+                    DiffLimit::unlimited(),
+                    None,
+                )
+                .messages
+        }
+        Offer::Messages(msgs) => msgs.clone(),
+        Offer::PerReceiver(per_receiver) => per_receiver.get(&to).cloned().unwrap_or_default(),
+    }
+}
@@ -0,0 +1,151 @@
+use std::{collections::BTreeSet, mem::Discriminant, time::Duration};
+
+use tmelcrypt::HashVal;
+
+use crate::{
+    invariants::Violation,
+    trace::{replay, Step, Trace},
+    Mutation,
+};
+
+/// Shrinks a trace whose replay violates an invariant, by removing players, ticks, banned-set entries and received messages for as long as the replay still violates an invariant of the same kind. Replays of the shrunk traces diverge from what they recorded, which is fine as long as the violation stays. Returns the smallest trace found, or None if the trace doesn't violate anything to begin with.
+pub fn shrink(trace: &Trace, mutation: Option<Mutation>) -> Option<Trace> {
+    let kind = kind_of(replay(trace, mutation).invariants.violation()?);
+    let reproduces = |trace: &Trace| {
+        replay(trace, mutation)
+            .invariants
+            .violation()
+            .is_some_and(|violation| kind_of(violation) == kind)
+    };
+    let mut best = trace.clone();
+    loop {
+        let before = size(&best);
+        let players: Vec<usize> = best.steps.keys().copied().collect();
+        remove_chunks(&mut best, players, &reproduces, |trace, players| {
+            trace.steps.retain(|player, _| !players.contains(player))
+        });
+        while let Some(shorter) = without_last_tick(&best) {
+            if !reproduces(&shorter) {
+                break;
+            }
+            best = shorter;
+        }
+        let bans: Vec<(usize, Duration, usize)> = best
+            .steps
+            .iter()
+            .flat_map(|(player, steps)| {
+                steps.iter().flat_map(move |(at, step)| match step {
+                    Step::Banned(banned) => banned.iter().map(|b| (*player, *at, *b)).collect(),
+                    _ => vec![],
+                })
+            })
+            .collect();
+        remove_chunks(&mut best, bans, &reproduces, |trace, bans| {
+            for (player, steps) in trace.steps.iter_mut() {
+                for (at, step) in steps.iter_mut() {
+                    if let Step::Banned(banned) = step {
+                        banned.retain(|b| !bans.contains(&(*player, *at, *b)));
+                    }
+                }
+            }
+        });
+        let deliveries: Vec<(usize, Duration, usize, HashVal)> = best
+            .steps
+            .iter()
+            .flat_map(|(player, steps)| {
+                steps.iter().filter_map(move |(at, step)| match step {
+                    Step::Received { from, hash, .. } => Some((*player, *at, *from, *hash)),
+                    _ => None,
+                })
+            })
+            .collect();
+        remove_chunks(&mut best, deliveries, &reproduces, |trace, deliveries| {
+            for (player, steps) in trace.steps.iter_mut() {
+                steps.retain(|(at, step)| match step {
+                    Step::Received { from, hash, .. } => {
+                        !deliveries.contains(&(*player, *at, *from, *hash))
+                    }
+                    _ => true,
+                });
+            }
+        });
+        if size(&best) == before {
+            return Some(best);
+        }
+    }
+}
+
+/// Tells violations of different kinds apart, whatever their details.
+fn kind_of(violation: &Violation) -> Discriminant<Violation> {
+    std::mem::discriminant(violation)
+}
+
+/// How many steps and banned-set entries there are, which shrinking only ever lowers.
+fn size(trace: &Trace) -> usize {
+    trace
+        .steps
+        .values()
+        .flatten()
+        .map(|(_, step)| match step {
+            Step::Banned(banned) => banned.len() + 1,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Removes as many of the given things from the trace as possible while it still reproduces, trying big chunks of them first, then ever smaller ones.
+fn remove_chunks<T: Clone + Ord>(
+    best: &mut Trace,
+    mut things: Vec<T>,
+    reproduces: &impl Fn(&Trace) -> bool,
+    remove: impl Fn(&mut Trace, &BTreeSet<T>),
+) {
+    let mut chunk = things.len();
+    while chunk > 0 {
+        let mut i = 0;
+        while i < things.len() {
+            let end = (i + chunk).min(things.len());
+            let mut candidate = best.clone();
+            remove(&mut candidate, &things[i..end].iter().cloned().collect());
+            if reproduces(&candidate) {
+                *best = candidate;
+                things.drain(i..end);
+            } else {
+                i = end;
+            }
+        }
+        chunk /= 2;
+    }
+}
+
+/// The trace without the last tick that any player started, nor anything after. Returns None if no player got to start a tick.
+fn without_last_tick(trace: &Trace) -> Option<Trace> {
+    let last = trace
+        .steps
+        .values()
+        .flatten()
+        .filter(|(_, step)| matches!(step, Step::PreTick(_)))
+        .map(|(at, _)| *at)
+        .max()?;
+    let mut shorter = trace.clone();
+    for steps in shorter.steps.values_mut() {
+        steps.retain(|(at, _)| *at < last);
+    }
+    Some(shorter)
+}
+
+#[cfg(all(test, feature = "mutation-testing"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shrinks_while_reproducing() {
+        let mutation = Some(Mutation::WeakThreshold);
+        let (invariants, trace) = crate::simulate(1, mutation);
+        let kind = kind_of(invariants.violation().expect("seed 1 should be caught"));
+        let shrunk = shrink(&trace, mutation).unwrap();
+        assert!(size(&shrunk) < size(&trace));
+        let violation = replay(&shrunk, mutation).invariants.violation().cloned();
+        assert_eq!(violation.map(|v| kind_of(&v)), Some(kind));
+    }
+}
@@ -0,0 +1,495 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt::Display,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_lite::future;
+use serde::{Deserialize, Serialize};
+use streamlette::{
+    Core, Decider, DeciderConfig, DiffMessage, Fatal, Message, MockClock, RejectReason, Timer,
+};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
+
+use crate::{invariants::Invariants, valid_proposal, Mutation, LIVENESS_BOUND, NONCE};
+
+/// What came out of half a tick: a decision, nothing yet, or a fatal error.
+pub type Outcome = Result<Option<Bytes>, String>;
+
+/// Something that happened to an honest player.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Step {
+    /// Until our next tick, we can't pull from these players.
+    Banned(BTreeSet<usize>),
+    /// We came up with this proposal.
+    Proposed(Bytes),
+    /// We ran [Decider::pre_tick].
+    PreTick(Outcome),
+    /// We ran [Decider::post_tick].
+    PostTick(Outcome),
+    /// We pulled a message from another player, and applied it to our core unless they were banned, in which case there is no result. Duplicates are left out, since applying them does nothing.
+    Received {
+        from: usize,
+        hash: HashVal,
+        msg: DiffMessage,
+        result: Option<Result<(), String>>,
+    },
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Banned(banned) => write!(f, "bans {:?}", banned),
+            Step::Proposed(body) => write!(f, "proposes {:?}", body),
+            Step::PreTick(outcome) => write!(f, "pre-tick: {:?}", outcome),
+            Step::PostTick(outcome) => write!(f, "post-tick: {:?}", outcome),
+            Step::Received {
+                from,
+                hash,
+                result: Some(result),
+                ..
+            } => write!(f, "applies {} from {}: {:?}", hash, from, result),
+            Step::Received {
+                from,
+                hash,
+                result: None,
+                ..
+            } => write!(f, "drops {} from {}", hash, from),
+        }
+    }
+}
+
+/// Everything that happened to the honest players during a run, which is enough to replay the run without the randomness that drove it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Trace {
+    pub seed: u128,
+    pub gst: Duration,
+    /// When the run ended.
+    pub end: Duration,
+    /// What happened to each honest player, in order, and when.
+    pub steps: BTreeMap<usize, Vec<(Duration, Step)>>,
+}
+
+impl Trace {
+    /// Creates an empty trace for the given honest players.
+    pub fn new(seed: u128, gst: Duration, honest: &BTreeSet<usize>) -> Self {
+        Self {
+            seed,
+            gst,
+            end: Duration::ZERO,
+            steps: honest.iter().map(|i| (*i, vec![])).collect(),
+        }
+    }
+
+    /// Loads a trace written by [Trace::save].
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        stdcode::deserialize(&std::fs::read(path)?)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+    }
+
+    /// Writes the trace to a file.
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        std::fs::write(path, stdcode::serialize(self).unwrap())
+    }
+}
+
+impl Display for Trace {
+    /// Lists every step of every player, in the order they happened.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "seed {}, GST at {:?}, ending at {:?}",
+            self.seed, self.gst, self.end
+        )?;
+        let mut steps: Vec<(Duration, usize, &Step)> = self
+            .steps
+            .iter()
+            .flat_map(|(i, steps)| steps.iter().map(move |(at, step)| (*at, *i, step)))
+            .collect();
+        steps.sort_by_key(|(at, _, _)| *at);
+        for (at, player, step) in steps {
+            writeln!(f, "[{:>12?}] {} {}", at, player, step)?;
+        }
+        Ok(())
+    }
+}
+
+/// Records the steps of one honest player into a shared [Trace].
+#[derive(Clone)]
+pub struct Recorder {
+    player: usize,
+    clock: MockClock,
+    trace: Arc<Mutex<Trace>>,
+}
+
+impl Recorder {
+    pub fn new(player: usize, clock: MockClock, trace: Arc<Mutex<Trace>>) -> Self {
+        Self {
+            player,
+            clock,
+            trace,
+        }
+    }
+
+    /// Records a step as happening right now.
+    pub fn record(&self, step: Step) {
+        let at = self.clock.elapsed();
+        self.trace
+            .lock()
+            .unwrap()
+            .steps
+            .entry(self.player)
+            .or_default()
+            .push((at, step));
+    }
+
+    /// Records a message pulled from `from`, along with the result of applying it, or None if `from` was banned. Duplicates are left out.
+    pub fn record_received(
+        &self,
+        from: usize,
+        msg: DiffMessage,
+        result: Option<&Result<(), RejectReason>>,
+    ) {
+        if result == Some(&Err(RejectReason::Duplicate)) {
+            return;
+        }
+        self.record(Step::Received {
+            from,
+            hash: hash_of(&msg),
+            msg,
+            result: result.map(applied),
+        });
+    }
+}
+
+/// Turns the result of half a tick into something that can be recorded and compared.
+pub fn outcome(result: &Result<Option<Bytes>, Fatal>) -> Outcome {
+    result.clone().map_err(|err| err.to_string())
+}
+
+/// Turns the result of applying a message into something that can be recorded and compared.
+fn applied(result: &Result<(), RejectReason>) -> Result<(), String> {
+    result.clone().map_err(|err| err.to_string())
+}
+
+fn hash_of(msg: &DiffMessage) -> HashVal {
+    match msg {
+        DiffMessage::Proposal(p) => p.chash(),
+        DiffMessage::Solicit(s) => s.chash(),
+        DiffMessage::Vote(v) => v.chash(),
+    }
+}
+
+/// Stands in for [crate::MockConfig] during a replay, feeding the Decider whatever the trace says it got.
+struct ReplayConfig {
+    participants: Vec<(Ed25519SK, u64)>,
+    index: usize,
+    clock: MockClock,
+    /// Our proposals, in the order we came up with them.
+    proposals: Mutex<VecDeque<Bytes>>,
+    /// What to apply on the next sync.
+    pending: Arc<Mutex<Vec<DiffMessage>>>,
+    /// What came of applying what was pending, in order.
+    results: Arc<Mutex<Vec<Result<(), RejectReason>>>>,
+    /// Everything we had as of the last sync with nothing pending.
+    everything: Arc<Mutex<Vec<DiffMessage>>>,
+    invariants: Arc<Mutex<Invariants>>,
+    #[cfg_attr(not(feature = "mutation-testing"), allow(dead_code))]
+    mutation: Option<Mutation>,
+}
+
+#[async_trait]
+impl DeciderConfig for ReplayConfig {
+    fn generate_proposal(&self) -> Bytes {
+        // code changes can make us propose more often than we used to
+        self.proposals
+            .lock()
+            .unwrap()
+            .pop_front()
+            .unwrap_or_else(|| format!("prop 0 from {}", self.index).into())
+    }
+
+    fn verify_proposal(&self, prop: &[u8]) -> bool {
+        valid_proposal(prop)
+    }
+
+    async fn sync_core(&self, core: &mut Core) {
+        self.invariants
+            .lock()
+            .unwrap()
+            .check_votes(self.index, core);
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        if pending.is_empty() {
+            *self.everything.lock().unwrap() = core.get_diff(&HashMap::new());
+        }
+        let results: Vec<_> = pending
+            .into_iter()
+            .map(|msg| core.apply_one_diff(msg))
+            .collect();
+        self.results.lock().unwrap().extend(results);
+        future::pending().await
+    }
+
+    fn vote_weights(&self) -> BTreeMap<Ed25519PK, u64> {
+        self.participants
+            .iter()
+            .map(|(k, v)| (k.to_public(), *v))
+            .collect()
+    }
+
+    fn seed(&self) -> u128 {
+        NONCE
+    }
+
+    fn my_secret(&self) -> Ed25519SK {
+        self.participants[self.index].0
+    }
+
+    fn timer(&self) -> Arc<dyn Timer> {
+        Arc::new(self.clock.clone())
+    }
+
+    #[cfg(feature = "mutation-testing")]
+    fn mutation(&self) -> Option<Mutation> {
+        self.mutation
+    }
+}
+
+/// A replayed honest player.
+struct Replayed {
+    decider: Decider,
+    pending: Arc<Mutex<Vec<DiffMessage>>>,
+    results: Arc<Mutex<Vec<Result<(), RejectReason>>>>,
+    everything: Arc<Mutex<Vec<DiffMessage>>>,
+    banned: BTreeSet<usize>,
+    done: bool,
+}
+
+impl Replayed {
+    /// Applies whatever is pending, or, with nothing pending, takes note of everything we have.
+    fn sync(&mut self) {
+        future::block_on(self.decider.sync_state(Some(Duration::ZERO)));
+    }
+
+    /// Applies a single message, returning what came of it.
+    fn apply(&mut self, msg: DiffMessage) -> Result<(), RejectReason> {
+        self.pending.lock().unwrap().push(msg);
+        self.sync();
+        self.results.lock().unwrap().pop().unwrap()
+    }
+}
+
+/// What came out of a replay.
+pub struct Replay {
+    pub invariants: Invariants,
+    /// The Decider of every replayed player, as it ended up.
+    pub deciders: BTreeMap<usize, Decider>,
+    /// Everywhere a Decider came out of a half-tick, or applying a message, differently than the trace says.
+    pub divergences: Vec<String>,
+}
+
+/// Replays the trace on a fresh Decider for every honest player in it, running each half-tick and applying each message when the trace says, except for messages from players that were banned at the time. Notes wherever the replay comes out differently than the trace says, such as after a change to the code, or after shrinking.
+pub fn replay(trace: &Trace, mutation: Option<Mutation>) -> Replay {
+    let participants = crate::participants();
+    let keys: Vec<Ed25519PK> = participants.iter().map(|(sk, _)| sk.to_public()).collect();
+    let clock = MockClock::new();
+    let invariants = Arc::new(Mutex::new(Invariants::new(
+        trace.seed,
+        trace.steps.keys().copied().collect(),
+        participants
+            .iter()
+            .map(|(sk, weight)| (sk.to_public(), *weight))
+            .collect(),
+        NONCE,
+        trace.gst,
+        LIVENESS_BOUND,
+        valid_proposal,
+    )));
+    let mut players: BTreeMap<usize, Replayed> = trace
+        .steps
+        .iter()
+        .map(|(&i, steps)| {
+            let pending: Arc<Mutex<Vec<DiffMessage>>> = Default::default();
+            let results: Arc<Mutex<Vec<Result<(), RejectReason>>>> = Default::default();
+            let everything: Arc<Mutex<Vec<DiffMessage>>> = Default::default();
+            let config = ReplayConfig {
+                participants: participants.clone(),
+                index: i,
+                clock: clock.clone(),
+                proposals: Mutex::new(
+                    steps
+                        .iter()
+                        .filter_map(|(_, step)| match step {
+                            Step::Proposed(body) => Some(body.clone()),
+                            _ => None,
+                        })
+                        .collect(),
+                ),
+                pending: pending.clone(),
+                results: results.clone(),
+                everything: everything.clone(),
+                invariants: invariants.clone(),
+                mutation,
+            };
+            let player = Replayed {
+                decider: Decider::new(config),
+                pending,
+                results,
+                everything,
+                banned: BTreeSet::new(),
+                done: false,
+            };
+            (i, player)
+        })
+        .collect();
+
+    let mut steps: Vec<(Duration, usize, &Step)> = trace
+        .steps
+        .iter()
+        .flat_map(|(i, steps)| steps.iter().map(move |(at, step)| (*at, *i, step)))
+        .collect();
+    // stable, so that each player's steps stay in order
+    steps.sort_by_key(|(at, _, _)| *at);
+    let mut proposed = BTreeSet::new();
+    let mut received = vec![];
+    let mut divergences = vec![];
+    for (at, i, step) in steps {
+        if at > clock.elapsed() {
+            invariants.lock().unwrap().check(clock.elapsed());
+            clock.advance(at - clock.elapsed());
+        }
+        let player = players.get_mut(&i).unwrap();
+        match step {
+            Step::Banned(banned) => player.banned = banned.clone(),
+            Step::Proposed(body) => {
+                if proposed.insert(body.clone()) {
+                    invariants
+                        .lock()
+                        .unwrap()
+                        .record_proposal(at, i, body.clone());
+                }
+            }
+            Step::Received {
+                from,
+                msg,
+                result: recorded,
+                ..
+            } => {
+                // whoever made it up proposed it, honest or not
+                if let DiffMessage::Proposal(p) = msg {
+                    let author = keys.iter().position(|k| *k == p.source).unwrap_or(*from);
+                    if proposed.insert(p.body.clone()) {
+                        invariants
+                            .lock()
+                            .unwrap()
+                            .record_proposal(at, author, p.body.clone());
+                    }
+                }
+                received.push(msg.clone());
+                let result = if player.banned.contains(from) {
+                    None
+                } else {
+                    Some(applied(&player.apply(msg.clone())))
+                };
+                if result != *recorded {
+                    divergences.push(format!(
+                        "{} diverged at {:?} on {} from {}: got {:?} rather than {:?}",
+                        i,
+                        at,
+                        hash_of(msg),
+                        from,
+                        result,
+                        recorded
+                    ));
+                }
+            }
+            Step::PreTick(recorded) | Step::PostTick(recorded) => {
+                if player.done {
+                    continue;
+                }
+                let result = if matches!(step, Step::PreTick(_)) {
+                    player.decider.pre_tick()
+                } else {
+                    player.decider.post_tick()
+                };
+                if outcome(&result) != *recorded {
+                    divergences.push(format!(
+                        "{} diverged at {:?}: got {:?} rather than {:?}",
+                        i,
+                        at,
+                        outcome(&result),
+                        recorded
+                    ));
+                }
+                let result = match result {
+                    Ok(None) => continue,
+                    Ok(Some(decision)) => Ok(decision),
+                    Err(err) => Err(err),
+                };
+                player.done = true;
+                let certificate = player.decider.finality_certificate().ok().flatten();
+                invariants
+                    .lock()
+                    .unwrap()
+                    .record_outcome(at, i, result, certificate);
+            }
+        }
+    }
+
+    for player in players.values_mut() {
+        player.sync();
+        received.extend(player.everything.lock().unwrap().drain(..));
+    }
+    let mut invariants = invariants.lock().unwrap().clone();
+    invariants.check(trace.end);
+    invariants.check_messages(received);
+    Replay {
+        invariants,
+        deciders: players
+            .into_iter()
+            .map(|(i, player)| (i, player.decider))
+            .collect(),
+        divergences,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_reproduces_run() {
+        let (original, trace) = crate::simulate(12345, None);
+        let trace: Trace = stdcode::deserialize(&stdcode::serialize(&trace).unwrap()).unwrap();
+        let replayed = replay(&trace, None);
+        assert_eq!(replayed.divergences, Vec::<String>::new());
+        let replayed = replayed.invariants;
+        assert!(original.violation().is_none());
+        assert!(replayed.violation().is_none());
+        assert_eq!(original.decisions(), replayed.decisions());
+    }
+
+    #[test]
+    fn bans_are_replayed() {
+        let (_, mut trace) = crate::simulate(12345, None);
+        let dropped = trace
+            .steps
+            .values()
+            .flatten()
+            .filter(|(_, step)| matches!(step, Step::Received { result: None, .. }))
+            .count();
+        assert!(dropped > 0);
+        for (_, step) in trace.steps.values_mut().flatten() {
+            if let Step::Banned(banned) = step {
+                banned.clear();
+            }
+        }
+        // everything that was dropped now gets applied instead
+        let replayed = replay(&trace, None);
+        assert!(replayed.divergences.len() >= dropped);
+    }
+}
@@ -0,0 +1,80 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use serde::{Deserialize, Serialize};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+use crate::{
+    msg::{Message, Proposal, Solicit, Vote},
+    params::ProtocolParams,
+};
+
+/// A self-contained proof that a proposal was finalized: the proposal, the chain of solicits building on it up to three consecutive-tick messages, and the votes notarizing those three messages. (With non-default [ProtocolParams], the number of messages is [ProtocolParams::depth] instead.)
+///
+/// Light clients can check a certificate with [FinalityCertificate::verify], knowing only the vote weights and nonce of the instance.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct FinalityCertificate {
+    pub proposal: Proposal,
+    /// The solicits from the proposal up to the last finalizing solicit, in order. The first solicit extends the proposal.
+    pub solicits: Vec<Solicit>,
+    /// Votes for the last three messages of the chain.
+    pub votes: Vec<Vote>,
+}
+
+impl FinalityCertificate {
+    /// Checks the certificate against the vote weights and nonce of an instance. If this returns true, [FinalityCertificate::proposal] was finalized in that instance, unless more than 1/3 of the vote weight is byzantine.
+    ///
+    /// Note that this does *not* check that the proposal passes the instance's validation function, or that the proposal and solicits came from the right leaders; a quorum of votes implies that honest players already checked that.
+    pub fn verify(&self, vote_weights: &BTreeMap<Ed25519PK, u64>, nonce: u128) -> bool {
+        self.verify_with_params(vote_weights, nonce, ProtocolParams::default())
+    }
+
+    /// Same as [FinalityCertificate::verify], but for an instance with non-default [ProtocolParams].
+    pub fn verify_with_params(
+        &self,
+        vote_weights: &BTreeMap<Ed25519PK, u64>,
+        nonce: u128,
+        params: ProtocolParams,
+    ) -> bool {
+        let proposal = &self.proposal;
+        if proposal.nonce != nonce || !proposal.verify_sig() {
+            return false;
+        }
+        // the chain must link up, and ticks must increase along it
+        let mut chain: Vec<(HashVal, u64)> = vec![(proposal.chash(), proposal.tick)];
+        for solicit in self.solicits.iter() {
+            let &(prev_hash, prev_tick) = chain.last().unwrap();
+            if solicit.nonce != nonce
+                || !solicit.verify_sig()
+                || solicit.previous != prev_hash
+                || solicit.tick <= prev_tick
+            {
+                return false;
+            }
+            chain.push((solicit.chash(), solicit.tick));
+        }
+        if params.depth == 0 || chain.len() < params.depth {
+            return false;
+        }
+        let window = &chain[chain.len() - params.depth..];
+        if !window.windows(2).all(|w| w[1].1 == w[0].1 + 1) {
+            return false;
+        }
+        // each of the last `depth` messages must be notarized
+        let mut tallies: HashMap<HashVal, u128> = HashMap::new();
+        let mut seen = HashSet::new();
+        for vote in self.votes.iter() {
+            if vote.nonce != nonce || !seen.insert((vote.voting_for, vote.source)) {
+                continue;
+            }
+            if let Some(weight) = vote_weights.get(&vote.source) {
+                if vote.verify_sig() {
+                    *tallies.entry(vote.voting_for).or_default() += *weight as u128;
+                }
+            }
+        }
+        let total_votes: u128 = vote_weights.values().map(|&w| w as u128).sum();
+        window.iter().all(|(hash, _)| {
+            params.is_quorum(tallies.get(hash).copied().unwrap_or_default(), total_votes)
+        })
+    }
+}
@@ -1,96 +1,203 @@
-use std::{collections::BTreeMap, time::Duration};
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
 
-use arrayref::array_ref;
 use async_trait::async_trait;
 use bytes::Bytes;
 use futures_lite::FutureExt;
 use tmelcrypt::{Ed25519PK, Ed25519SK};
 
-use crate::core::Core;
+use crate::{
+    certificate::FinalityCertificate,
+    core::{Core, DiffMessage},
+    error::{Fatal, RejectReason, SnapshotError},
+    evidence::EquivocationEvidence,
+    guard::SigningGuard,
+    leader::{LeaderSchedule, WeightedRandom},
+    params::ProtocolParams,
+    storage::{JournalEntry, Storage},
+    tick_schedule::{Exponential, TickSchedule},
+    timer::Timer,
+    vrf::VrfElection,
+};
+
+#[cfg(feature = "mutation-testing")]
+use crate::mutation::Mutation;
+
+/// The shortest slice [Decider::tick_to_end] syncs for while waiting for a tick to end, so that time always moves on, even on a mock clock with absurdly short ticks.
+const MIN_SLICE: Duration = Duration::from_millis(1);
 
 /// Encapsulates a single instance of Streamlette, that eventually comes to consensus on a single decision.
 pub struct Decider {
-    config: Box<dyn DeciderConfig>,
+    config: Arc<dyn DeciderConfig>,
     core: Core,
     tick: u64,
+    mid_tick: bool,
+    journal: Option<Arc<dyn Storage>>,
+    timer: Arc<dyn Timer>,
 
     decision: Option<Bytes>,
 }
 
 impl Decider {
     /// Creates a new Decider.
+    ///
+    /// Panics if [DeciderConfig::protocol_params] returns parameters that make no sense, such as a depth of 0, or if [DeciderConfig::vrf_election] expects no leaders at all.
     pub fn new(config: impl DeciderConfig) -> Self {
-        let seed = config.seed();
-        let total_votes: u64 = config.vote_weights().values().sum();
-        let weights = config.vote_weights();
-        let core = Core::new(config.seed(), config.vote_weights(), move |tick| {
-            // we first randomly and fairly pick a number between 0 and total_votes.
-            let random_point = {
-                let mut state = seed.wrapping_add(tick as u128);
-                let mut point = u64::MAX;
-                while point >= total_votes {
-                    let v = tmelcrypt::hash_single(&state.to_be_bytes());
-                    state = u128::from_be_bytes(*array_ref![v, 0, 16]);
-                    point = (state >> (total_votes as u128).leading_zeros()) as u64;
-                }
-                point
-            };
-            // using that random number, we then pick a player according to its weight.
-            // we add the weights together until we exceed the random number; the staker we're at when that happens is the selected one
-            let mut sum = 0;
-            for (&pk, &weight) in weights.iter() {
-                sum += weight;
-                if sum > random_point {
-                    return pk;
-                }
-            }
-            unreachable!()
-        });
+        let config: Arc<dyn DeciderConfig> = Arc::new(config);
+        let verifier = config.clone();
+        let mut core = Core::new(
+            config.seed(),
+            config.vote_weights(),
+            config.leader_schedule(),
+            move |prop| verifier.verify_proposal(prop),
+            config.protocol_params(),
+        );
+        if let Some(election) = config.vrf_election() {
+            core.set_vrf_election(election);
+        }
+        if let Some(guard) = config.signing_guard() {
+            core.set_signing_guard(guard);
+        }
+        #[cfg(feature = "mutation-testing")]
+        if let Some(mutation) = config.mutation() {
+            core.set_mutation(mutation);
+        }
         Self {
-            config: Box::new(config),
+            timer: config.timer(),
+            config,
             core,
             tick: 0,
+            mid_tick: false,
+            journal: None,
             decision: None,
         }
     }
 
+    /// Creates a Decider that journals everything it signs or accepts, as well as its progress through the ticks, to the given [Storage] before acting on it. If the storage already contains a journal, the Decider picks up where the journal left off.
+    ///
+    /// A restarted player must use this, rather than [Decider::new], to avoid signing something different for a tick it already signed for, which is equivocation.
+    pub fn recover(config: impl DeciderConfig, storage: impl Storage) -> Result<Self, Fatal> {
+        let mut decider = Self::new(config);
+        let entries = storage
+            .replay()
+            .map_err(|err| Fatal::Storage(err.to_string()))?;
+        decider.tick = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::Tick(tick) => Some(*tick),
+                JournalEntry::Message(_) => None,
+            })
+            .max()
+            .unwrap_or_default();
+        // nothing we accepted could have been past the tick after ours, except from imported snapshots
+        let last_tick = entries
+            .iter()
+            .filter_map(|entry| match entry {
+                JournalEntry::Message(DiffMessage::Proposal(p)) => Some(p.tick),
+                JournalEntry::Message(DiffMessage::Solicit(s)) => Some(s.tick),
+                _ => None,
+            })
+            .max()
+            .unwrap_or_default();
+        decider.core.set_max_tick(last_tick.max(decider.tick + 1));
+        for entry in entries {
+            if let JournalEntry::Message(msg) = entry {
+                match decider.core.apply_one_diff(msg) {
+                    // twins are journaled when set aside, and go into the tree once their journaled votes notarize them
+                    Ok(()) | Err(RejectReason::Duplicate) | Err(RejectReason::Equivocation) => {}
+                    Err(err) => {
+                        return Err(Fatal::Storage(format!(
+                            "journaled message could not be replayed: {}",
+                            err
+                        )))
+                    }
+                }
+            }
+        }
+        decider.core.set_max_tick(decider.tick + 1);
+        let storage: Arc<dyn Storage> = Arc::new(storage);
+        decider.core.set_journal(storage.clone());
+        decider.journal = Some(storage);
+        Ok(decider)
+    }
+
     /// Prints the graphivz representation of everything we have now.
     pub fn debug_graphviz(&self) -> String {
         self.core.debug_graphviz()
     }
 
+    /// Returns a certificate proving that the decision was finalized, if it has been.
+    pub fn finality_certificate(&self) -> Result<Option<FinalityCertificate>, Fatal> {
+        self.core.finality_certificate()
+    }
+
+    /// Returns evidence of every equivocation we've seen so far, at most one per player.
+    pub fn equivocation_evidence(&self) -> Vec<EquivocationEvidence> {
+        self.core.equivocation_evidence()
+    }
+
+    /// Exports a snapshot of everything we have. See [Core::export_snapshot].
+    pub fn export_snapshot(&self) -> Bytes {
+        self.core.export_snapshot()
+    }
+
+    /// Imports a snapshot, such as one taken from another player. See [Core::import_snapshot].
+    pub fn import_snapshot(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        self.core.import_snapshot(snapshot)
+    }
+
+    /// Runs the next half-tick of the Decider: [Decider::pre_tick] if the current tick has not started, [Decider::post_tick] otherwise. If the decision has been made, return it.
+    ///
+    /// Does no I/O. Call [Decider::sync_state] between consecutive calls.
+    pub fn tick(&mut self) -> Result<Option<Bytes>, Fatal> {
+        if self.mid_tick {
+            self.post_tick()
+        } else {
+            self.pre_tick()
+        }
+    }
+
     /// Runs the first half of the tick of the Decider. If the decision has been made, return it.
     ///
     /// Does no I/O. Either use [Decider::tick_to_end], or call the [Decider::sync_state] method periodically.
-    pub fn pre_tick(&mut self) -> Option<Bytes> {
+    pub fn pre_tick(&mut self) -> Result<Option<Bytes>, Fatal> {
         self.core.set_max_tick(self.tick + 1);
-        if let Some(v) = self.core.get_finalized() {
-            self.decision = Some(v.body.clone());
-        }
-        if self.decision.is_some() {
-            return self.decision.clone();
+        if let Some(decision) = self.check_decision()? {
+            return Ok(Some(decision));
         }
         self.core
             .insert_my_prop_or_solicit(self.tick, self.config.my_secret(), || {
                 self.config.generate_proposal()
-            });
-        None
+            })?;
+        self.mid_tick = true;
+        Ok(None)
     }
 
-    /// Runs the first half of the tick of the Decider. If the decision has been made, return it.
+    /// Runs the second half of the tick of the Decider. If the decision has been made, return it.
     ///
     /// Does no I/O. Either use [Decider::tick_to_end], or call the [Decider::sync_state] method periodically.
-    pub fn post_tick(&mut self) -> Option<Bytes> {
-        if let Some(v) = self.core.get_finalized() {
-            self.decision = Some(v.body.clone());
-        }
-        if self.decision.is_some() {
-            return self.decision.clone();
+    pub fn post_tick(&mut self) -> Result<Option<Bytes>, Fatal> {
+        if let Some(decision) = self.check_decision()? {
+            return Ok(Some(decision));
         }
         // do our logic
-        self.core.insert_my_votes(self.config.my_secret());
+        self.core.insert_my_votes(self.config.my_secret())?;
+        if let Some(journal) = &self.journal {
+            journal
+                .append(&JournalEntry::Tick(self.tick + 1))
+                .map_err(|err| Fatal::Storage(err.to_string()))?;
+        }
         self.tick += 1;
-        None
+        self.mid_tick = false;
+        Ok(None)
+    }
+
+    /// Checks the core for fatal conditions, then for a decision.
+    fn check_decision(&mut self) -> Result<Option<Bytes>, Fatal> {
+        self.core.check_fatal()?;
+        if let Some(v) = self.core.get_finalized()? {
+            self.decision = Some(v.body.clone());
+        }
+        Ok(self.decision.clone())
     }
 
     /// Synchronized state, given a timeout.
@@ -99,7 +206,7 @@ impl Decider {
             self.config
                 .sync_core(&mut self.core)
                 .or(async {
-                    async_io::Timer::after(timeout).await;
+                    self.timer.sleep(timeout).await;
                 })
                 .await
         } else {
@@ -107,23 +214,36 @@ impl Decider {
         }
     }
 
-    /// Ticks this decider until the decision has been made. We use a gradually increasing synchronization interval.
+    /// Ticks this decider until the decision has been made, timing ticks according to [DeciderConfig::tick_schedule].
     ///
     /// If liveness is required, it is generally *not* okay to drop the [Decider] after this function returns. Otherwise, some participants' `tick_to_end` may not return. Instead, the decider should be kept running (by calling `sync_state`) until you're sure everyone has gotten the message.
-    pub async fn tick_to_end(&mut self) -> Bytes {
-        let mut interval = 1.0f64;
+    pub async fn tick_to_end(&mut self) -> Result<Bytes, Fatal> {
+        let mut schedule = self.config.tick_schedule();
         loop {
-            if let Some(result) = self.pre_tick() {
-                return result;
+            let half = schedule.next_interval() / 2;
+            if let Some(result) = self.pre_tick()? {
+                return Ok(result);
+            }
+            self.sync_state(Some(half)).await;
+            let tick = self.tick;
+            if let Some(result) = self.post_tick()? {
+                return Ok(result);
             }
-            self.sync_state(Duration::from_secs_f64(interval / 2.0).into())
-                .await;
-            if let Some(result) = self.post_tick() {
-                return result;
+            // sync in slices, so that we can tell how long it took for our votes to notarize something
+            let voted_at = self.timer.now();
+            let mut notarized_after = None;
+            loop {
+                let elapsed = self.timer.now().saturating_duration_since(voted_at);
+                if notarized_after.is_none() && self.core.tick_notarized(tick) {
+                    notarized_after = Some(elapsed);
+                }
+                if elapsed >= half {
+                    break;
+                }
+                self.sync_state(Some((half / 8).max(MIN_SLICE).min(half - elapsed)))
+                    .await;
             }
-            self.sync_state(Duration::from_secs_f64(interval / 2.0).into())
-                .await;
-            interval *= 1.05;
+            schedule.observe(notarized_after);
         }
     }
 }
@@ -136,7 +256,7 @@ pub trait DeciderConfig: Sync + Send + 'static {
     /// Generates a new proposal.
     fn generate_proposal(&self) -> Bytes;
 
-    /// Returns whether a proposed decision is valid.
+    /// Returns whether a proposed decision is valid. Proposals failing this check are never accepted into the [Core], and thus never voted on.
     fn verify_proposal(&self, prop: &[u8]) -> bool;
 
     /// Synchronizes, in a best-effort fashion, this "Core" state with other players on the network. Should *never return* and be cancel-safe; the Decider itself will timeout this as needed.
@@ -150,4 +270,45 @@ pub trait DeciderConfig: Sync + Send + 'static {
 
     /// Returns our secret key.
     fn my_secret(&self) -> Ed25519SK;
+
+    /// Returns the schedule deciding who leads each tick. By default, this is [WeightedRandom] over [DeciderConfig::seed] and [DeciderConfig::vote_weights]. Ignored if [DeciderConfig::vrf_election] is set.
+    fn leader_schedule(&self) -> Arc<dyn LeaderSchedule> {
+        Arc::new(WeightedRandom::new(self.seed(), self.vote_weights()))
+    }
+
+    /// Returns a fresh schedule for how long each tick lasts in [Decider::tick_to_end]. By default, this is [Exponential::default].
+    fn tick_schedule(&self) -> Box<dyn TickSchedule> {
+        Box::new(Exponential::default())
+    }
+
+    /// Returns the parameters of the protocol. Must return the same value every time! The default preserves the safety guarantees of Streamlet; see [ProtocolParams] before changing it.
+    fn protocol_params(&self) -> ProtocolParams {
+        ProtocolParams::default()
+    }
+
+    /// Returns the parameters for electing leaders secretly instead, through a VRF. By default, leaders are elected publicly through [DeciderConfig::leader_schedule].
+    fn vrf_election(&self) -> Option<VrfElection> {
+        None
+    }
+
+    /// Returns the source of time for timeouts and tick timing. By default, this uses async-io if that feature is enabled, or tokio otherwise.
+    #[cfg(any(feature = "async-io", feature = "tokio"))]
+    fn timer(&self) -> Arc<dyn Timer> {
+        crate::timer::default_timer()
+    }
+
+    /// Returns the source of time for timeouts and tick timing. Must be implemented, since neither the async-io nor the tokio feature is enabled.
+    #[cfg(not(any(feature = "async-io", feature = "tokio")))]
+    fn timer(&self) -> Arc<dyn Timer>;
+
+    /// Returns a guard to consult before signing anything, as protection against accidentally running two copies of the same player. By default, there is none.
+    fn signing_guard(&self) -> Option<Arc<dyn SigningGuard>> {
+        None
+    }
+
+    /// Returns a known-bad variant of the logic to run instead, for checking that a fuzzer notices. By default, there is none.
+    #[cfg(feature = "mutation-testing")]
+    fn mutation(&self) -> Option<Mutation> {
+        None
+    }
 }
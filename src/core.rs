@@ -1,5 +1,5 @@
 use std::{
-    collections::{BTreeMap, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
@@ -9,9 +9,36 @@ use std::{
 use bytes::Bytes;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
 use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal};
 
-use crate::msg::{Message, Proposal, Solicit, Vote};
+use crate::{
+    certificate::FinalityCertificate,
+    error::{Fatal, RejectReason, SnapshotError},
+    evidence::EquivocationEvidence,
+    guard::{MessageKind, SigningGuard},
+    leader::LeaderSchedule,
+    msg::{Message, Proposal, Solicit, Vote},
+    params::{exceeds_fraction, ProtocolParams},
+    storage::{JournalEntry, Storage},
+    vrf::{self, VrfElection},
+};
+
+#[cfg(feature = "mutation-testing")]
+use crate::mutation::Mutation;
+
+/// Whether the given known-bad variant of the logic, a `Mutation`, is in effect in the given [Core]. Without the mutation-testing feature, this is always false, and none of the mutated logic gets compiled in.
+macro_rules! mutated {
+    ($core:ident, $mutation:ident) => {{
+        #[cfg(feature = "mutation-testing")]
+        let mutated = $core.mutation == Some(Mutation::$mutation);
+        #[cfg(not(feature = "mutation-testing"))]
+        let mutated = false;
+        mutated
+    }};
+}
+
+type ProposalVerifier = Arc<dyn Fn(&[u8]) -> bool + Send + Sync + 'static>;
 
 /// Core consensus logic. Stores the tree, etc.
 #[derive(Clone)]
@@ -19,14 +46,31 @@ pub struct Core {
     valid_proposals: BTreeMap<HashVal, Proposal>,
     vote_solicits: BTreeMap<HashVal, Solicit>,
     votes: BTreeMap<HashVal, Vote>,
-    tick_source: HashSet<(u64, Ed25519PK)>,
+    // indices over the above, maintained on insertion
+    chain_len: HashMap<HashVal, u64>,
+    voters: HashMap<HashVal, BTreeMap<Ed25519PK, HashVal>>,
+    tallies: HashMap<HashVal, u128>,
+    notarized_by_len: BTreeMap<u64, BTreeSet<HashVal>>,
+    notarized_ticks: BTreeSet<u64>,
+    tick_voters: HashMap<u64, HashSet<Ed25519PK>>,
+    tick_digests: BTreeMap<u64, HashVal>,
+    tick_source: HashMap<(u64, Ed25519PK), HashVal>,
+    equivocations: BTreeMap<Ed25519PK, EquivocationEvidence>,
+    twins: BTreeMap<HashVal, Twin>,
     nonce: u128,
 
-    tick_to_leader: Arc<dyn Fn(u64) -> Ed25519PK + Send + Sync + 'static>,
+    leader_schedule: Arc<dyn LeaderSchedule>,
+    vrf_election: Option<VrfElection>,
+    verify_proposal: ProposalVerifier,
     vote_map: BTreeMap<Ed25519PK, u64>,
-    total_votes: u64,
+    total_votes: u128,
+    params: ProtocolParams,
+    #[cfg(feature = "mutation-testing")]
+    mutation: Option<Mutation>,
 
     max_tick: Arc<AtomicU64>,
+    journal: Option<Arc<dyn Storage>>,
+    signing_guard: Option<Arc<dyn SigningGuard>>,
 }
 
 /// An enum of different possible messages, used to represent a "diff" between different [Core]s.
@@ -37,12 +81,132 @@ pub enum DiffMessage {
     Vote(Vote),
 }
 
+/// Limits on the size of a diff produced by [Core::get_diff_limited].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiffLimit {
+    /// Maximum total size of the messages, in stdcode-encoded bytes.
+    pub max_bytes: usize,
+    /// Maximum number of messages.
+    pub max_msgs: usize,
+}
+
+impl DiffLimit {
+    /// No limit at all.
+    pub fn unlimited() -> Self {
+        Self {
+            max_bytes: usize::MAX,
+            max_msgs: usize::MAX,
+        }
+    }
+}
+
+/// A diff produced by [Core::get_diff_limited].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LimitedDiff {
+    pub messages: Vec<DiffMessage>,
+    /// If there are more messages that didn't fit within the limit, where to continue from.
+    pub next: Option<DiffCursor>,
+}
+
+/// A position within a diff produced by [Core::get_diff_limited], used to continue a diff that was cut short.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct DiffCursor {
+    tick: u64,
+    target: HashVal,
+    vote: Option<HashVal>,
+}
+
+/// A compact summary of a [Core], produced by [Core::compact_summary].
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CompactSummary {
+    /// Ticks before this one are only summarized together, in [CompactSummary::before_horizon].
+    pub horizon: u64,
+    /// The XOR of the digests of all ticks before the horizon.
+    pub before_horizon: HashVal,
+    /// Mapping from each tick at or after the horizon to the XOR of the hashes of all messages for that tick. Votes count towards the tick of what they vote for.
+    pub ticks: BTreeMap<u64, HashVal>,
+}
+
+/// The version of the snapshot format written by [Core::export_snapshot].
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// Everything in a snapshot produced by [Core::export_snapshot], following the version byte.
+#[derive(Serialize, Deserialize)]
+struct Snapshot {
+    nonce: u128,
+    proposals: Vec<Proposal>,
+    solicits: Vec<Solicit>,
+    votes: Vec<Vote>,
+    tick_sources: Vec<(u64, Ed25519PK, HashVal)>,
+    equivocations: Vec<EquivocationEvidence>,
+}
+
+/// How many conflicting proposals or solicits we keep aside for each tick and player, bounding how much an equivocating leader can make us store.
+const MAX_TWINS: usize = 4;
+
+/// A proposal or solicit conflicting with what its source already sent for the same tick, kept aside along with the votes for it. An equivocating leader may show different players different messages, so the one we saw second may well be the one that gets notarized. If it does, we add it to the tree anyway, so that we can follow the chains building on it.
+///
+/// Twins and the votes for them are journaled as they come, but left out of summaries, diffs and snapshots until they are notarized. Until then, peers that have a twin in their tree keep offering it to us, which [MAX_TWINS] bounds.
+#[derive(Clone)]
+struct Twin {
+    tick: u64,
+    source: Ed25519PK,
+    msg: DiffMessage,
+    votes: BTreeMap<Ed25519PK, Vote>,
+}
+
+fn xor_into(acc: &mut HashVal, h: HashVal) {
+    for (a, b) in acc.0.iter_mut().zip(h.0) {
+        *a ^= b
+    }
+}
+
 impl Core {
     /// Sets the max tick of the core.
     pub(crate) fn set_max_tick(&self, tick: u64) {
         self.max_tick.store(tick, Ordering::SeqCst);
     }
 
+    /// Sets the storage that every message is journaled to before it is inserted.
+    pub(crate) fn set_journal(&mut self, journal: Arc<dyn Storage>) {
+        self.journal = Some(journal);
+    }
+
+    /// Elects leaders secretly with the given VRF election, instead of with the leader schedule. Panics if nobody could ever be eligible.
+    pub(crate) fn set_vrf_election(&mut self, election: VrfElection) {
+        election.assert_valid();
+        self.vrf_election = Some(election);
+    }
+
+    /// Injects a known-bad variant of the logic. The ones changing the threshold or the finalization depth do so through the [ProtocolParams].
+    #[cfg(feature = "mutation-testing")]
+    pub(crate) fn set_mutation(&mut self, mutation: Mutation) {
+        match mutation {
+            Mutation::WeakThreshold => {
+                self.params.threshold_numerator = 1;
+                self.params.threshold_denominator = 2;
+            }
+            Mutation::TwoTickFinalization => self.params.depth = 2,
+            Mutation::VoteOffLncTips | Mutation::SkipTickSourceCheck => {}
+        }
+        self.mutation = Some(mutation);
+    }
+
+    /// Sets the guard consulted before we sign anything.
+    pub(crate) fn set_signing_guard(&mut self, guard: Arc<dyn SigningGuard>) {
+        self.signing_guard = Some(guard);
+    }
+
+    /// Journals a message that passed every check, right before it is inserted.
+    fn journal(&self, msg: impl FnOnce() -> DiffMessage) -> Result<(), RejectReason> {
+        if let Some(journal) = &self.journal {
+            journal
+                .append(&JournalEntry::Message(msg()))
+                .map_err(|err| RejectReason::Storage(err.to_string()))?;
+        }
+        Ok(())
+    }
+
     /// Gets the max tick of the core.
     pub(crate) fn max_tick(&self) -> u64 {
         self.max_tick.load(Ordering::SeqCst)
@@ -58,61 +222,300 @@ impl Core {
             toret.insert(h, HashVal::default());
         }
         for (h, vote) in self.votes.iter() {
-            let xx = toret.entry(vote.voting_for).or_default();
-            let mut b = xx.0;
-            for i in 0..32 {
-                b[i] ^= h[i]
-            }
-            *xx = HashVal(b)
+            xor_into(toret.entry(vote.voting_for).or_default(), *h);
         }
         toret
     }
 
-    /// Obtains a diff, given somebody else's summary. We return an ordered vector of messages.
-    pub fn get_diff(&self, their_summary: &HashMap<HashVal, HashVal>) -> Vec<DiffMessage> {
-        let our_summary = self.summary();
+    /// Obtain a compact summary of the whole status, with one digest for each of the latest `recent_ticks` ticks we have messages for, and a single digest for everything older. Its size does not grow with the message tree, so peers can cheaply check whether they're in sync.
+    ///
+    /// A full sync round with compact summaries goes like this:
+    /// - We send our compact summary to a peer, who uses [Core::mismatched_ticks] to find which ticks we differ on. If none, we're done.
+    /// - We send the peer [Core::summary_for_ticks] for those ticks.
+    /// - The peer replies with [Core::get_diff_for_ticks].
+    ///
+    /// Since new messages almost always belong to recent ticks, mostly-identical peers only ever drill down into a few ticks.
+    pub fn compact_summary(&self, recent_ticks: u64) -> CompactSummary {
+        let horizon = self
+            .tick_digests
+            .keys()
+            .next_back()
+            .map(|last| (last + 1).saturating_sub(recent_ticks))
+            .unwrap_or_default();
+        CompactSummary {
+            horizon,
+            before_horizon: self.digest_before(horizon),
+            ticks: self
+                .tick_digests
+                .range(horizon..)
+                .map(|(k, v)| (*k, *v))
+                .collect(),
+        }
+    }
 
-        let mut toret = vec![];
-        let votes_by_candidate: HashMap<HashVal, Vec<Vote>> =
-            self.votes.values().fold(HashMap::new(), |mut hm, v| {
-                hm.entry(v.voting_for).or_default().push(v.clone());
-                hm
-            });
-        for (hash, prop) in self.valid_proposals.iter() {
-            if our_summary.get(hash) != their_summary.get(hash) {
-                if !their_summary.contains_key(hash) {
-                    toret.push(DiffMessage::Proposal(prop.clone()));
-                }
-                if let Some(v) = votes_by_candidate.get(hash) {
-                    for v in v.iter() {
-                        toret.push(DiffMessage::Vote(v.clone()))
-                    }
+    /// Given somebody else's compact summary, returns the ticks where our messages may differ. If we differ anywhere before their horizon, this includes all our ticks before it.
+    pub fn mismatched_ticks(&self, their_summary: &CompactSummary) -> BTreeSet<u64> {
+        let mut toret: BTreeSet<u64> = self
+            .tick_digests
+            .range(their_summary.horizon..)
+            .map(|(k, _)| k)
+            .chain(their_summary.ticks.keys())
+            .filter(|tick| self.tick_digests.get(tick) != their_summary.ticks.get(tick))
+            .copied()
+            .collect();
+        if self.digest_before(their_summary.horizon) != their_summary.before_horizon {
+            toret.extend(
+                self.tick_digests
+                    .range(..their_summary.horizon)
+                    .map(|(k, _)| k),
+            );
+        }
+        toret
+    }
+
+    fn digest_before(&self, horizon: u64) -> HashVal {
+        let mut toret = HashVal::default();
+        for digest in self.tick_digests.range(..horizon).map(|(_, v)| v) {
+            xor_into(&mut toret, *digest);
+        }
+        toret
+    }
+
+    /// Obtain the same summary as [Core::summary], but only covering messages for the given ticks.
+    pub fn summary_for_ticks(&self, ticks: &BTreeSet<u64>) -> HashMap<HashVal, HashVal> {
+        let mut toret = self.summary();
+        toret.retain(|h, _| ticks.contains(&self.tick_of(*h)));
+        toret
+    }
+
+    /// Obtains a diff for only the given ticks, given somebody else's summary for those ticks (from [Core::summary_for_ticks]). Otherwise, this works exactly like [Core::get_diff_limited].
+    pub fn get_diff_for_ticks(
+        &self,
+        ticks: &BTreeSet<u64>,
+        their_summary: &HashMap<HashVal, HashVal>,
+        limit: DiffLimit,
+        after: Option<DiffCursor>,
+    ) -> LimitedDiff {
+        self.diff_inner(their_summary, limit, after, |tick| ticks.contains(&tick))
+    }
+
+    /// Returns evidence for every player caught equivocating so far, at most one piece per player.
+    pub fn equivocation_evidence(&self) -> Vec<EquivocationEvidence> {
+        self.equivocations.values().cloned().collect()
+    }
+
+    /// Exports all the messages we have, as well as who sent what for each tick and any evidence of equivocation, as a versioned, stdcode-encoded snapshot. See [Core::import_snapshot].
+    pub fn export_snapshot(&self) -> Bytes {
+        let snapshot = Snapshot {
+            nonce: self.nonce,
+            proposals: self.valid_proposals.values().cloned().collect(),
+            solicits: self.vote_solicits.values().cloned().collect(),
+            votes: self.votes.values().cloned().collect(),
+            tick_sources: self
+                .tick_source
+                .iter()
+                .map(|(&(tick, source), &hash)| (tick, source, hash))
+                .sorted()
+                .collect(),
+            equivocations: self.equivocations.values().cloned().collect(),
+        };
+        let mut toret = vec![SNAPSHOT_VERSION];
+        toret.extend_from_slice(&snapshot.stdcode());
+        toret.into()
+    }
+
+    /// Imports a snapshot produced by [Core::export_snapshot], adding everything in it to what we already have. Nothing is trusted: every message goes through the same checks as in [Core::apply_one_diff], against *our* vote weights and leaders. Either the whole snapshot is imported, or nothing is, and only what did get imported is journaled, once it all is.
+    ///
+    /// Where we saw a different proposal or solicit first than whoever took the snapshot, theirs is a twin to us, and joins our tree only if the snapshot shows it notarized.
+    pub fn import_snapshot(&mut self, snapshot: &[u8]) -> Result<(), SnapshotError> {
+        let (&version, snapshot) = snapshot.split_first().ok_or(SnapshotError::Malformed)?;
+        if version != SNAPSHOT_VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let snapshot: Snapshot =
+            stdcode::deserialize(snapshot).map_err(|_| SnapshotError::Malformed)?;
+        if snapshot.nonce != self.nonce {
+            return Err(SnapshotError::WrongNonce(snapshot.nonce));
+        }
+        // nothing gets journaled until the whole snapshot is in
+        let mut new = self.clone();
+        new.journal = None;
+        // messages from an archived instance may well be past the tick we're at
+        let last_tick = snapshot
+            .proposals
+            .iter()
+            .map(|p| p.tick)
+            .chain(snapshot.solicits.iter().map(|s| s.tick))
+            .max()
+            .unwrap_or_default();
+        if last_tick > self.max_tick() {
+            new.max_tick = Arc::new(AtomicU64::new(last_tick));
+        }
+        // parents always have earlier ticks, and every proposal or solicit is directly followed by its votes, so that twins get notarized before anything builds on them. Within a tick, what each player sent first comes before any twins.
+        let registered: HashSet<HashVal> = snapshot
+            .tick_sources
+            .iter()
+            .map(|(_, _, hash)| *hash)
+            .collect();
+        let mut votes = snapshot
+            .votes
+            .into_iter()
+            .into_group_map_by(|v| v.voting_for);
+        let targets = snapshot
+            .proposals
+            .into_iter()
+            .map(|p| (p.tick, p.chash(), DiffMessage::Proposal(p)))
+            .chain(
+                snapshot
+                    .solicits
+                    .into_iter()
+                    .map(|s| (s.tick, s.chash(), DiffMessage::Solicit(s))),
+            )
+            .sorted_by_key(|(tick, hash, _)| (*tick, !registered.contains(hash)))
+            .collect_vec();
+        let mut messages = vec![];
+        for (_, hash, target) in targets {
+            messages.push(target);
+            let votes = votes.remove(&hash).unwrap_or_default();
+            messages.extend(votes.into_iter().map(DiffMessage::Vote));
+        }
+        // votes for things that aren't in the snapshot
+        messages.extend(votes.into_values().flatten().map(DiffMessage::Vote));
+        let mut accepted = vec![];
+        for msg in messages {
+            let hash = match &msg {
+                DiffMessage::Proposal(p) => p.chash(),
+                DiffMessage::Solicit(s) => s.chash(),
+                DiffMessage::Vote(v) => v.chash(),
+            };
+            match new.apply_one_diff(msg.clone()) {
+                Ok(()) => accepted.push((hash, msg)),
+                // twins are added once their votes notarize them
+                Err(RejectReason::Equivocation) if new.twins.contains_key(&hash) => {
+                    accepted.push((hash, msg))
                 }
+                Err(RejectReason::Duplicate) | Err(RejectReason::Equivocation) => {}
+                Err(err) => return Err(SnapshotError::Rejected(hash, err)),
             }
         }
-        for (hash, solc) in self.vote_solicits.iter() {
-            if our_summary.get(hash) != their_summary.get(hash) {
-                if !their_summary.contains_key(hash) {
-                    toret.push(DiffMessage::Solicit(solc.clone()));
-                }
-                if let Some(v) = votes_by_candidate.get(hash) {
-                    for v in v.iter() {
-                        toret.push(DiffMessage::Vote(v.clone()))
-                    }
-                }
+        // where we already saw something else first, what the snapshot saw first is just a twin to us
+        for (tick, source, hash) in snapshot.tick_sources {
+            if !self.tick_source.contains_key(&(tick, source))
+                && new.tick_source.get(&(tick, source)) != Some(&hash)
+            {
+                return Err(SnapshotError::InconsistentTickSources);
             }
         }
-        // sort by epoch
-        toret.sort_unstable_by_key(|s| match s {
-            DiffMessage::Proposal(p) => p.tick,
-            DiffMessage::Solicit(s) => s.tick,
-            DiffMessage::Vote(_) => u64::MAX,
+        for evidence in snapshot.equivocations {
+            if !evidence.verify(self.nonce) {
+                return Err(SnapshotError::BadEvidence);
+            }
+            new.check_participant(evidence.offender())
+                .map_err(|_| SnapshotError::BadEvidence)?;
+            new.equivocations
+                .entry(evidence.offender())
+                .or_insert(evidence);
+        }
+        for (hash, msg) in accepted {
+            self.journal(|| msg)
+                .map_err(|err| SnapshotError::Rejected(hash, err))?;
+        }
+        new.max_tick = self.max_tick.clone();
+        new.journal = self.journal.clone();
+        *self = new;
+        Ok(())
+    }
+
+    /// Obtains a diff, given somebody else's summary. We return an ordered vector of messages.
+    pub fn get_diff(&self, their_summary: &HashMap<HashVal, HashVal>) -> Vec<DiffMessage> {
+        self.get_diff_limited(their_summary, DiffLimit::unlimited(), None)
+            .messages
+    }
+
+    /// Obtains a diff, given somebody else's summary, limited in size. The messages are in causal order: every proposal or solicit comes before anything building on it, and before the votes for it. Thus, applying any prefix of the diff in order never fails due to missing parents.
+    ///
+    /// If the diff was cut short, [LimitedDiff::next] is set, and the peer should ask again with an updated summary, passing it back as `after` to skip what it was already sent. (Summaries can't tell us *which* votes for something a peer is missing, so without the cursor we'd resend the same votes.) To always make progress, the first message is returned even if it alone exceeds the limit.
+    pub fn get_diff_limited(
+        &self,
+        their_summary: &HashMap<HashVal, HashVal>,
+        limit: DiffLimit,
+        after: Option<DiffCursor>,
+    ) -> LimitedDiff {
+        self.diff_inner(their_summary, limit, after, |_| true)
+    }
+
+    fn diff_inner(
+        &self,
+        their_summary: &HashMap<HashVal, HashVal>,
+        limit: DiffLimit,
+        after: Option<DiffCursor>,
+        tick_filter: impl Fn(u64) -> bool,
+    ) -> LimitedDiff {
+        let our_summary = self.summary();
+        let targets = self
+            .valid_proposals
+            .iter()
+            .map(|(h, p)| (p.tick, *h, DiffMessage::Proposal(p.clone())))
+            .chain(
+                self.vote_solicits
+                    .iter()
+                    .map(|(h, s)| (s.tick, *h, DiffMessage::Solicit(s.clone()))),
+            )
+            .filter(|(tick, hash, _)| {
+                tick_filter(*tick) && our_summary.get(hash) != their_summary.get(hash)
+            })
+            // parents always have strictly smaller ticks than their children
+            .sorted_by_key(|(tick, hash, _)| (*tick, *hash));
+        let positioned = targets.flat_map(|(tick, hash, target)| {
+            let target = (!their_summary.contains_key(&hash)).then(|| {
+                let cursor = DiffCursor {
+                    tick,
+                    target: hash,
+                    vote: None,
+                };
+                (cursor, target)
+            });
+            let votes = self
+                .votes_for(hash)
+                .map(|v| (v.chash(), v))
+                .sorted_by_key(|(vh, _)| *vh)
+                .map(move |(vh, v)| {
+                    let cursor = DiffCursor {
+                        tick,
+                        target: hash,
+                        vote: Some(vh),
+                    };
+                    (cursor, DiffMessage::Vote(v.clone()))
+                });
+            target.into_iter().chain(votes)
         });
+
+        let mut toret = LimitedDiff {
+            messages: vec![],
+            next: None,
+        };
+        let mut total_bytes = 0;
+        let mut last = None;
+        for (cursor, dmsg) in positioned {
+            if after.map(|after| cursor <= after).unwrap_or(false) {
+                continue;
+            }
+            let size = dmsg.stdcode().len();
+            if !toret.messages.is_empty()
+                && (toret.messages.len() >= limit.max_msgs || total_bytes + size > limit.max_bytes)
+            {
+                toret.next = last;
+                break;
+            }
+            total_bytes += size;
+            toret.messages.push(dmsg);
+            last = Some(cursor);
+        }
         toret
     }
 
-    /// Applies a particular DiffMessage
-    pub fn apply_one_diff(&mut self, dmsg: DiffMessage) -> anyhow::Result<()> {
+    /// Applies a particular DiffMessage, returning why it was rejected if it was.
+    pub fn apply_one_diff(&mut self, dmsg: DiffMessage) -> Result<(), RejectReason> {
         match dmsg {
             DiffMessage::Proposal(p) => self.insert_proposal(p),
             DiffMessage::Solicit(s) => self.insert_solicit(s),
@@ -124,79 +527,259 @@ impl Core {
     pub(crate) fn new(
         nonce: u128,
         player_votes: impl IntoIterator<Item = (Ed25519PK, u64)>,
-        tick_to_leader: impl Fn(u64) -> Ed25519PK + Send + Sync + 'static,
+        leader_schedule: impl LeaderSchedule,
+        verify_proposal: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+        params: ProtocolParams,
     ) -> Self {
+        params.assert_valid();
         let vote_map = player_votes.into_iter().collect::<BTreeMap<_, _>>();
-        let total_votes = vote_map.values().copied().sum();
+        let total_votes = vote_map.values().map(|&w| w as u128).sum();
         Core {
             valid_proposals: Default::default(),
             vote_solicits: Default::default(),
             votes: Default::default(),
+            chain_len: Default::default(),
+            voters: Default::default(),
+            tallies: Default::default(),
+            notarized_by_len: Default::default(),
+            notarized_ticks: Default::default(),
+            tick_voters: Default::default(),
+            tick_digests: Default::default(),
             tick_source: Default::default(),
+            equivocations: Default::default(),
+            twins: Default::default(),
             nonce,
-            tick_to_leader: Arc::new(tick_to_leader),
+            leader_schedule: Arc::new(leader_schedule),
+            vrf_election: None,
+            verify_proposal: Arc::new(verify_proposal),
             vote_map,
             total_votes,
+            params,
+            #[cfg(feature = "mutation-testing")]
+            mutation: None,
             max_tick: Arc::new(AtomicU64::new(1)),
+            journal: None,
+            signing_guard: None,
         }
     }
 
-    /// Insert *my* votes into the tree. We vote for everything that extends from a longest notarized chain; there cannot be duplicates within an epoch because of the tick_source thing.
-    pub(crate) fn insert_my_votes(&mut self, my_sk: Ed25519SK) {
+    /// Insert *my* votes into the tree. We vote for everything that extends from a longest notarized chain, except for notarized twins; there cannot be duplicates within an epoch because of the tick_source thing.
+    pub(crate) fn insert_my_votes(&mut self, my_sk: Ed25519SK) -> Result<(), Fatal> {
         let tips: HashSet<HashVal> = self.get_lnc_tips().into_iter().collect();
-        if tips.is_empty() {
+        let targets = if tips.is_empty() {
             log::debug!("tips are empty, so we vote for all the proposal");
-            // we vote for all the proposals --- they must all be valid to vote for due to checks when adding them
-            for prop in self.valid_proposals.keys().copied().collect_vec() {
-                let vote = Vote::new(self.nonce, prop, my_sk);
-                self.insert_vote(vote)
-                    .expect("own vote for a proposal could not be inserted");
-            }
+            // we vote for all the proposals --- they must all be valid to vote for due to checks when adding them (including verify_proposal)
+            self.valid_proposals.keys().copied().collect_vec()
         } else {
             // we vote for every solicit that *points to* the tip of a LNC.
-            let mut to_insert = vec![];
-            for (hash, solicit) in self.vote_solicits.iter() {
-                if tips.contains(&solicit.previous) {
-                    to_insert.push(Vote::new(self.nonce, *hash, my_sk));
-                }
+            self.vote_solicits
+                .iter()
+                .filter(|(_, solicit)| {
+                    tips.contains(&solicit.previous) || mutated!(self, VoteOffLncTips)
+                })
+                .map(|(hash, _)| *hash)
+                .collect_vec()
+        };
+        let targets = targets
+            .into_iter()
+            .filter(|target| self.is_first_seen(*target) || mutated!(self, SkipTickSourceCheck))
+            .collect_vec();
+        let targets = if self.vrf_election.is_some() {
+            self.one_per_tick(targets, my_sk.to_public())
+        } else {
+            targets
+        };
+        for target in targets {
+            self.insert_my_vote(target, my_sk)
+                .map_err(|err| match err {
+                    RejectReason::Storage(err) => Fatal::Storage(err),
+                    err => Fatal::Internal(format!(
+                        "own vote for {} could not be inserted: {}",
+                        target, err
+                    )),
+                })?;
+        }
+        Ok(())
+    }
+
+    /// With secretly elected leaders, several proposals or solicits may compete for the same tick, and we must vote for at most one of them. We pick the one with the lowest VRF output, unless we already voted for another.
+    fn one_per_tick(&self, targets: Vec<HashVal>, me: Ed25519PK) -> Vec<HashVal> {
+        let mut best: BTreeMap<u64, (u64, HashVal)> = BTreeMap::new();
+        for target in targets {
+            let tick = self.tick_of(target);
+            if self
+                .tick_voters
+                .get(&tick)
+                .is_some_and(|voters| voters.contains(&me))
+            {
+                continue;
             }
-            for vote in to_insert {
-                self.insert_vote(vote)
-                    .expect("own vote for a solicit could not be inserted")
+            let output = self.vrf_output_of(target);
+            let entry = best.entry(tick).or_insert((output, target));
+            if output < entry.0 {
+                *entry = (output, target);
             }
         }
+        best.into_values().map(|(_, target)| target).collect()
+    }
+
+    /// Gets the VRF output proving the leadership of a proposal or solicit we have.
+    fn vrf_output_of(&self, h: HashVal) -> u64 {
+        let (tick, source, proof) = if let Some(p) = self.valid_proposals.get(&h) {
+            (p.tick, p.source, &p.vrf_proof)
+        } else if let Some(s) = self.vote_solicits.get(&h) {
+            (s.tick, s.source, &s.vrf_proof)
+        } else {
+            return u64::MAX;
+        };
+        vrf::verify(&source, &VrfElection::alpha(self.nonce, tick), proof).unwrap_or(u64::MAX)
+    }
+
+    /// Checks whether the given player may lead the given tick, given the VRF proof that came with its message.
+    fn may_lead(&self, tick: u64, source: Ed25519PK, vrf_proof: &[u8]) -> bool {
+        if let Some(election) = self.vrf_election {
+            vrf::verify(&source, &VrfElection::alpha(self.nonce, tick), vrf_proof).is_some_and(
+                |output| {
+                    election.is_eligible(
+                        output,
+                        self.vote_map.get(&source).copied().unwrap_or_default(),
+                        self.total_votes,
+                    )
+                },
+            )
+        } else {
+            // a proof nobody asked for would only let the leader make twins of its messages
+            vrf_proof.is_empty() && self.leader_schedule.leader(tick) == source
+        }
+    }
+
+    /// Returns the VRF proof to attach to *my* proposal or solicit for the given tick, or None if I may not lead it.
+    fn my_leadership(&self, tick: u64, my_sk: Ed25519SK) -> Option<Bytes> {
+        if let Some(election) = self.vrf_election {
+            let (proof, output) = vrf::prove(my_sk, &VrfElection::alpha(self.nonce, tick));
+            let weight = self
+                .vote_map
+                .get(&my_sk.to_public())
+                .copied()
+                .unwrap_or_default();
+            election
+                .is_eligible(output, weight, self.total_votes)
+                .then(|| proof.into())
+        } else {
+            (self.leader_schedule.leader(tick) == my_sk.to_public()).then(Bytes::new)
+        }
+    }
+
+    /// Inserts *my* vote for the given target. Voting again for something we already voted for is fine, and does nothing.
+    fn insert_my_vote(&mut self, target: HashVal, my_sk: Ed25519SK) -> Result<(), RejectReason> {
+        if self
+            .voters
+            .get(&target)
+            .is_some_and(|voters| voters.contains_key(&my_sk.to_public()))
+        {
+            return Ok(());
+        }
+        let vote = Vote::unsigned(self.nonce, target, my_sk.to_public());
+        if !self.guard_approves(self.tick_of(target), MessageKind::Vote, vote.chash()) {
+            return Ok(());
+        }
+        self.insert_vote(vote.signed(my_sk))
     }
 
-    /// Insert *my* proposal or solicit. If it's not my turn, literally do nothing.
+    /// Asks the signing guard, if there is one, whether we may sign the given message.
+    fn guard_approves(&self, tick: u64, kind: MessageKind, hash: HashVal) -> bool {
+        self.signing_guard
+            .as_ref()
+            .is_none_or(|guard| guard.approve(self.nonce, tick, kind, hash))
+    }
+
+    /// Insert *my* proposal or solicit. If it's not my turn, or I already sent something for this tick (say, before a restart), literally do nothing.
     pub(crate) fn insert_my_prop_or_solicit(
         &mut self,
         tick: u64,
         my_sk: Ed25519SK,
         gen_prop: impl FnOnce() -> Bytes,
-    ) {
-        if (self.tick_to_leader)(tick) != my_sk.to_public() {
-            return; // not my turn
+    ) -> Result<(), Fatal> {
+        let vrf_proof = if let Some(proof) = self.my_leadership(tick, my_sk) {
+            proof
+        } else {
+            return Ok(()); // not my turn
+        };
+        if self.tick_source.contains_key(&(tick, my_sk.to_public())) {
+            return Ok(()); // signing anything else would be equivocation
         }
         let tips = self.get_lnc_tips();
         if let Some(&tip) = tips.first() {
             log::debug!("we have a LNC, so we insert a solicit");
             // we arbitrarily picked a longest-notarized-chain tip. send a solicit extending from it.
-            let solicit = Solicit::new(self.nonce, tick, tip, my_sk);
-            if let Err(err) = self.insert_solicit(solicit) {
-                log::warn!("self-insert solicit failed: {}", err);
+            let mut solicit = Solicit::unsigned(self.nonce, tick, tip, my_sk.to_public());
+            solicit.vrf_proof = vrf_proof;
+            if !self.guard_approves(tick, MessageKind::Solicit, solicit.chash()) {
+                return Ok(());
+            }
+            match self.insert_solicit(solicit.signed(my_sk)) {
+                Ok(()) => {}
+                Err(RejectReason::Storage(err)) => return Err(Fatal::Storage(err)),
+                Err(err) => log::warn!("self-insert solicit failed: {}", err),
             }
         } else {
             log::debug!("we do NOT have a LNC, so we insert a proposal");
             // shoot, we need to insert a proposal
             let proposal = gen_prop();
-            let proposal = Proposal::new(self.nonce, tick, proposal, my_sk);
-            self.insert_proposal(proposal)
-                .expect("could not insert my OWN proposal!");
+            let mut proposal = Proposal::unsigned(self.nonce, tick, proposal, my_sk.to_public());
+            proposal.vrf_proof = vrf_proof;
+            if !self.guard_approves(tick, MessageKind::Proposal, proposal.chash()) {
+                return Ok(());
+            }
+            match self.insert_proposal(proposal.signed(my_sk)) {
+                Ok(()) => {}
+                // our own validation function disagreeing with our own proposal generator isn't a consensus failure
+                Err(err @ RejectReason::InvalidProposal) => {
+                    log::warn!("self-insert proposal failed: {}", err)
+                }
+                Err(RejectReason::Storage(err)) => return Err(Fatal::Storage(err)),
+                Err(err) => {
+                    return Err(Fatal::Internal(format!(
+                        "could not insert my OWN proposal: {}",
+                        err
+                    )))
+                }
+            }
         }
+        Ok(())
+    }
+
+    /// Obtains the finalized proposal, if such a proposal exists. If two different proposals are both finalized, returns a [Fatal] error.
+    pub(crate) fn get_finalized(&self) -> Result<Option<&Proposal>, Fatal> {
+        Ok(self
+            .finalizing_chain()?
+            .map(|chain| &self.valid_proposals[&chain[0]]))
     }
 
-    /// Obtains the finalized proposal, if such a proposal exists.
-    pub(crate) fn get_finalized(&self) -> Option<&Proposal> {
+    /// Produces a [FinalityCertificate] proving that the finalized proposal, if any, was finalized.
+    pub fn finality_certificate(&self) -> Result<Option<FinalityCertificate>, Fatal> {
+        let chain = if let Some(chain) = self.finalizing_chain()? {
+            chain
+        } else {
+            return Ok(None);
+        };
+        let notarized = &chain[chain.len() - self.params.depth..];
+        Ok(Some(FinalityCertificate {
+            proposal: self.valid_proposals[&chain[0]].clone(),
+            solicits: chain[1..]
+                .iter()
+                .map(|h| self.vote_solicits[h].clone())
+                .collect(),
+            votes: notarized
+                .iter()
+                .flat_map(|h| self.votes_for(*h))
+                .cloned()
+                .collect(),
+        }))
+    }
+
+    /// Finds the chain that finalizes a proposal, ordered from the proposal up to the last of the *notarized messages with consecutive tick numbers* that finalize it. Normally, there are three of those.
+    fn finalizing_chain(&self) -> Result<Option<Vec<HashVal>>, Fatal> {
         // tips are solicits that do not have any other solicits pointing to them
         let lnc = self.get_lnc_tips();
         let notarized_tips = self
@@ -204,171 +787,370 @@ impl Core {
             .keys()
             .filter(|hash| lnc.contains(hash))
             .copied();
+        let mut finalized: Option<Vec<HashVal>> = None;
         for tip in notarized_tips {
-            // we go all the way back to a proposal, checking whether we see *three consecutive tick numbers*.
-            let mut tick_numbers = vec![self.vote_solicits[&tip].tick];
+            // we go all the way back to a proposal, checking whether we see *enough consecutive tick numbers*.
+            let mut chain = vec![];
             let mut tip_ptr = tip;
-            let tr;
             loop {
+                chain.push(tip_ptr);
                 if let Some(solicit) = self.vote_solicits.get(&tip_ptr) {
-                    tick_numbers.push(solicit.tick);
                     tip_ptr = solicit.previous;
-                } else if let Some(prop) = self.valid_proposals.get(&tip_ptr) {
-                    tick_numbers.push(prop.tick);
-                    tr = prop;
+                } else if self.valid_proposals.contains_key(&tip_ptr) {
                     break;
                 } else {
-                    panic!("string of vote solicits that dangle at the end?!?!?!")
+                    return Err(Fatal::Internal(format!(
+                        "string of vote solicits from {} dangles at {}",
+                        tip, tip_ptr
+                    )));
                 }
             }
-            let mut this_is_it = false;
-            for window in tick_numbers.windows(3) {
+            for (i, window) in chain.windows(self.params.depth).enumerate() {
                 // DESCENDING ticks
-                if window[0] == window[1] + 1 && window[1] == window[2] + 1 {
-                    this_is_it = true;
+                let ticks = window.iter().map(|h| self.tick_of(*h)).collect_vec();
+                if ticks.windows(2).all(|w| w[0] == w[1] + 1)
+                    && window.iter().all(|h| self.is_notarized(*h))
+                {
+                    let this = chain[i..].iter().rev().copied().collect_vec();
+                    match &finalized {
+                        Some(existing) if existing[0] != this[0] => {
+                            return Err(Fatal::ConflictingFinalizations(existing[0], this[0]))
+                        }
+                        _ => finalized = Some(this),
+                    }
                     break;
                 }
             }
-            if this_is_it {
-                return Some(tr);
-            }
         }
-        None
+        Ok(finalized)
+    }
+
+    /// Gets the tick of a proposal or solicit we have.
+    fn tick_of(&self, h: HashVal) -> u64 {
+        self.vote_solicits
+            .get(&h)
+            .map(|s| s.tick)
+            .or_else(|| self.valid_proposals.get(&h).map(|p| p.tick))
+            .unwrap_or_default()
+    }
+
+    /// Checks for unrecoverable conditions that are not tied to finalization, such as more than 1/3 of the vote weight equivocating.
+    pub(crate) fn check_fatal(&self) -> Result<(), Fatal> {
+        let equivocated: u128 = self
+            .equivocations
+            .keys()
+            .map(|pk| self.vote_map.get(pk).copied().unwrap_or_default() as u128)
+            .sum();
+        if exceeds_fraction(equivocated, self.total_votes, 1, 3) {
+            return Err(Fatal::Equivocation(
+                self.equivocations.keys().copied().collect(),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether any proposal or solicit for the given tick is notarized.
+    pub(crate) fn tick_notarized(&self, tick: u64) -> bool {
+        self.notarized_ticks.contains(&tick)
     }
 
     /// Obtains the tips of the longest notarized chain(s).
     pub(crate) fn get_lnc_tips(&self) -> Vec<HashVal> {
-        let mut memo = HashMap::new();
-        let mut hash_and_len = self
-            .valid_proposals
-            .keys()
-            .chain(self.vote_solicits.keys())
-            .filter(|hash| self.is_notarized(**hash))
-            .map(|h| (*h, self.lookup_len(*h, &mut memo)))
-            .collect_vec();
-        hash_and_len.sort_unstable_by_key(|a| a.1);
-        hash_and_len.reverse();
-        let longest_len = hash_and_len.first().map(|s| s.1);
-        hash_and_len
-            .into_iter()
-            .take_while(|s| Some(s.1) == longest_len)
-            .map(|s| s.0)
-            .sorted()
-            .collect_vec()
+        self.notarized_by_len
+            .values()
+            .next_back()
+            .map(|tips| tips.iter().copied().collect_vec())
+            .unwrap_or_default()
     }
 
     /// Insert a proposal.
-    pub(crate) fn insert_proposal(&mut self, prop: Proposal) -> anyhow::Result<()> {
-        if !prop.verify_sig() && (self.tick_to_leader)(prop.tick) == prop.source {
-            anyhow::bail!("bad signature")
+    pub(crate) fn insert_proposal(&mut self, prop: Proposal) -> Result<(), RejectReason> {
+        let hash = prop.chash();
+        if self.valid_proposals.contains_key(&hash) {
+            return Err(RejectReason::Duplicate);
         }
+        // messages for other instances are benign, whoever signed them, so this goes before any check that would blame the author
         if prop.nonce != self.nonce {
-            anyhow::bail!("bad nonce")
+            return Err(RejectReason::WrongNonce(prop.nonce));
+        }
+        self.check_participant(prop.source)?;
+        if !prop.verify_sig() {
+            return Err(RejectReason::BadSignature);
+        }
+        if !self.may_lead(prop.tick, prop.source, &prop.vrf_proof) {
+            return Err(RejectReason::WrongLeader);
         }
         if prop.tick > self.max_tick() {
-            anyhow::bail!(
-                "proposal has tick {} > max tick {}",
-                prop.tick,
-                self.max_tick()
-            )
+            return Err(RejectReason::FutureTick {
+                tick: prop.tick,
+                max_tick: self.max_tick(),
+            });
         }
-        if !self.tick_source.insert((prop.tick, prop.source)) {
-            anyhow::bail!("this player already sent something for this tick")
+        if !(self.verify_proposal)(&prop.body) {
+            return Err(RejectReason::InvalidProposal);
         }
-        // Now we insert this into the system
-        self.valid_proposals.insert(prop.chash(), prop);
+        if let Some(&existing) = self
+            .tick_source
+            .get(&(prop.tick, prop.source))
+            .filter(|_| !mutated!(self, SkipTickSourceCheck))
+        {
+            self.record_equivocation(existing, DiffMessage::Proposal(prop.clone()));
+            self.set_aside(hash, prop.tick, prop.source, DiffMessage::Proposal(prop))?;
+            return Err(RejectReason::Equivocation);
+        }
+        self.journal(|| DiffMessage::Proposal(prop.clone()))?;
+        self.store_proposal(prop);
         Ok(())
     }
 
+    /// Adds a proposal that passed all the checks, and was journaled, to the tree.
+    fn store_proposal(&mut self, prop: Proposal) {
+        let hash = prop.chash();
+        self.tick_source
+            .entry((prop.tick, prop.source))
+            .or_insert(hash);
+        // Now we insert this into the system
+        xor_into(self.tick_digests.entry(prop.tick).or_default(), hash);
+        self.valid_proposals.insert(hash, prop);
+        self.chain_len.insert(hash, 0);
+    }
+
     /// Insert a vote.
-    pub(crate) fn insert_vote(&mut self, vote: Vote) -> anyhow::Result<()> {
-        if !vote.verify_sig() {
-            anyhow::bail!("bad signature")
+    pub(crate) fn insert_vote(&mut self, vote: Vote) -> Result<(), RejectReason> {
+        let hash = vote.chash();
+        if self.votes.contains_key(&hash) {
+            return Err(RejectReason::Duplicate);
         }
         if vote.nonce != self.nonce {
-            anyhow::bail!("bad nonce")
+            return Err(RejectReason::WrongNonce(vote.nonce));
+        }
+        self.check_participant(vote.source)?;
+        if !vote.verify_sig() {
+            return Err(RejectReason::BadSignature);
+        }
+        if self.twins.contains_key(&vote.voting_for) {
+            return self.insert_twin_vote(vote);
         }
         // check that this  vote actually votes for something
         if !self.vote_solicits.contains_key(&vote.voting_for)
             && !self.valid_proposals.contains_key(&vote.voting_for)
         {
-            anyhow::bail!("vote not voting for anything")
+            return Err(RejectReason::MissingParent(vote.voting_for));
         }
+        self.journal(|| DiffMessage::Vote(vote.clone()))?;
+        self.store_vote(vote);
+        Ok(())
+    }
 
-        self.votes.insert(vote.chash(), vote.clone());
+    /// Adds a vote that passed all the checks, was journaled, and whose target we have, to the tree.
+    fn store_vote(&mut self, vote: Vote) {
+        let hash = vote.chash();
+        let target = vote.voting_for;
+        let tick = self.tick_of(target);
+        self.tick_voters
+            .entry(tick)
+            .or_default()
+            .insert(vote.source);
+        let voters = self.voters.entry(target).or_default();
+        if voters.insert(vote.source, hash).is_none() {
+            let tally = self.tallies.entry(target).or_default();
+            let was_notarized = self.params.is_quorum(*tally, self.total_votes);
+            *tally += self.vote_map[&vote.source] as u128;
+            if !was_notarized && self.params.is_quorum(*tally, self.total_votes) {
+                self.notarized_by_len
+                    .entry(self.chain_len[&target])
+                    .or_default()
+                    .insert(target);
+                self.notarized_ticks.insert(tick);
+            }
+        }
         log::debug!(
             "{:?} voting for {}, who now has {} votes",
             vote.source,
-            vote.voting_for,
-            self.votes
-                .values()
-                .filter(|v| v.voting_for == vote.voting_for)
-                .count()
+            target,
+            voters.len()
         );
-        Ok(())
+        xor_into(self.tick_digests.entry(tick).or_default(), hash);
+        self.votes.insert(hash, vote);
     }
 
     /// Inserts a vote solicitation.
-    pub(crate) fn insert_solicit(&mut self, solicit: Solicit) -> anyhow::Result<()> {
-        if !solicit.verify_sig() && (self.tick_to_leader)(solicit.tick) == solicit.source {
-            anyhow::bail!("bad signature")
+    pub(crate) fn insert_solicit(&mut self, solicit: Solicit) -> Result<(), RejectReason> {
+        let hash = solicit.chash();
+        if self.vote_solicits.contains_key(&hash) {
+            return Err(RejectReason::Duplicate);
         }
         if solicit.nonce != self.nonce {
-            anyhow::bail!("bad nonce")
+            return Err(RejectReason::WrongNonce(solicit.nonce));
+        }
+        self.check_participant(solicit.source)?;
+        if !solicit.verify_sig() {
+            return Err(RejectReason::BadSignature);
+        }
+        if !self.may_lead(solicit.tick, solicit.source, &solicit.vrf_proof) {
+            return Err(RejectReason::WrongLeader);
         }
         if solicit.tick > self.max_tick() {
-            anyhow::bail!(
-                "proposal has tick {} > max tick {}",
-                solicit.tick,
-                self.max_tick()
-            )
+            return Err(RejectReason::FutureTick {
+                tick: solicit.tick,
+                max_tick: self.max_tick(),
+            });
         }
-        if !self.vote_solicits.contains_key(&solicit.previous)
-            && !self.valid_proposals.contains_key(&solicit.previous)
-        {
-            anyhow::bail!("solicit not growing from anything")
-        }
-        if solicit.tick
-            <= self
-                .vote_solicits
-                .get(&solicit.previous)
-                .map(|s| s.tick)
-                .or_else(|| self.valid_proposals.get(&solicit.previous).map(|s| s.tick))
-                .unwrap()
+        let previous_tick = self
+            .vote_solicits
+            .get(&solicit.previous)
+            .map(|s| s.tick)
+            .or_else(|| self.valid_proposals.get(&solicit.previous).map(|s| s.tick))
+            .ok_or(RejectReason::MissingParent(solicit.previous))?;
+        if solicit.tick <= previous_tick {
+            return Err(RejectReason::TickNotIncreasing);
+        }
+        if let Some(&existing) = self
+            .tick_source
+            .get(&(solicit.tick, solicit.source))
+            .filter(|_| !mutated!(self, SkipTickSourceCheck))
         {
-            anyhow::bail!("tick of vote solicit cannot go backwards in time lol")
+            self.record_equivocation(existing, DiffMessage::Solicit(solicit.clone()));
+            self.set_aside(
+                hash,
+                solicit.tick,
+                solicit.source,
+                DiffMessage::Solicit(solicit),
+            )?;
+            return Err(RejectReason::Equivocation);
         }
-        if !self.tick_source.insert((solicit.tick, solicit.source)) {
-            anyhow::bail!("this player already sent something for this tick")
+        self.journal(|| DiffMessage::Solicit(solicit.clone()))?;
+        self.store_solicit(solicit);
+        Ok(())
+    }
+
+    /// Adds a solicit that passed all the checks, was journaled, and whose parent we have, to the tree.
+    fn store_solicit(&mut self, solicit: Solicit) {
+        let hash = solicit.chash();
+        self.tick_source
+            .entry((solicit.tick, solicit.source))
+            .or_insert(hash);
+
+        self.chain_len
+            .insert(hash, self.chain_len[&solicit.previous] + 1);
+        xor_into(self.tick_digests.entry(solicit.tick).or_default(), hash);
+        self.vote_solicits.insert(hash, solicit);
+    }
+
+    /// Checks that the given player is in the vote weight map. Messages from anybody else are rejected outright, so that outsiders cannot make us store anything.
+    fn check_participant(&self, source: Ed25519PK) -> Result<(), RejectReason> {
+        if self.vote_map.contains_key(&source) {
+            Ok(())
+        } else {
+            Err(RejectReason::UnknownPlayer(source))
         }
+    }
+
+    /// Records evidence that the source of `second` equivocated, given the hash of the message it conflicts with. We keep only one piece of evidence per player, since that's all it takes to convict them.
+    fn record_equivocation(&mut self, existing: HashVal, second: DiffMessage) {
+        let first = if let Some(p) = self.valid_proposals.get(&existing) {
+            DiffMessage::Proposal(p.clone())
+        } else if let Some(s) = self.vote_solicits.get(&existing) {
+            DiffMessage::Solicit(s.clone())
+        } else {
+            return;
+        };
+        let evidence = EquivocationEvidence { first, second };
+        log::warn!("caught {:?} equivocating", evidence.offender());
+        self.equivocations
+            .entry(evidence.offender())
+            .or_insert(evidence);
+    }
 
-        self.vote_solicits.insert(solicit.chash(), solicit);
+    /// Keeps a conflicting proposal or solicit aside, journaling it, in case it gets notarized. See [Twin].
+    fn set_aside(
+        &mut self,
+        hash: HashVal,
+        tick: u64,
+        source: Ed25519PK,
+        msg: DiffMessage,
+    ) -> Result<(), RejectReason> {
+        let kept = self
+            .twins
+            .values()
+            .filter(|twin| twin.tick == tick && twin.source == source)
+            .count();
+        if kept < MAX_TWINS && !self.twins.contains_key(&hash) {
+            self.journal(|| msg.clone())?;
+            self.twins.insert(
+                hash,
+                Twin {
+                    tick,
+                    source,
+                    msg,
+                    votes: BTreeMap::new(),
+                },
+            );
+        }
         Ok(())
     }
 
-    fn lookup_len(&self, h: HashVal, memo: &mut HashMap<HashVal, u64>) -> u64 {
-        if let Some(v) = memo.get(&h) {
-            *v
-        } else {
-            let v = if self.valid_proposals.contains_key(&h) {
-                0
-            } else if let Some(v) = self.vote_solicits.get(&h) {
-                self.lookup_len(v.previous, memo) + 1
-            } else {
-                0
-            };
-            memo.insert(h, v);
-            v
+    /// Journals and holds on to a vote for a twin, adding the twin to the tree, then its votes, once they notarize it. The vote must already have been checked.
+    fn insert_twin_vote(&mut self, vote: Vote) -> Result<(), RejectReason> {
+        let target = vote.voting_for;
+        if self.twins[&target].votes.contains_key(&vote.source) {
+            return Err(RejectReason::Duplicate);
         }
+        self.journal(|| DiffMessage::Vote(vote.clone()))?;
+        let twin = self.twins.get_mut(&target).unwrap();
+        twin.votes.insert(vote.source, vote);
+        let tally = twin
+            .votes
+            .keys()
+            .map(|voter| self.vote_map[voter] as u128)
+            .sum();
+        if !self.params.is_quorum(tally, self.total_votes) {
+            return Ok(());
+        }
+        let twin = self.twins.remove(&target).unwrap();
+        log::warn!(
+            "twin {} from {:?} was notarized, so we follow it as well",
+            target,
+            twin.source
+        );
+        // all of it was journaled already
+        match twin.msg {
+            DiffMessage::Proposal(prop) => self.store_proposal(prop),
+            DiffMessage::Solicit(solicit) => self.store_solicit(solicit),
+            DiffMessage::Vote(_) => unreachable!(),
+        }
+        for vote in twin.votes.into_values() {
+            self.store_vote(vote);
+        }
+        Ok(())
+    }
+
+    /// Whether the given proposal or solicit is the first one we saw from its source for its tick, as opposed to a notarized twin.
+    fn is_first_seen(&self, h: HashVal) -> bool {
+        let source = if let Some(p) = self.valid_proposals.get(&h) {
+            p.source
+        } else if let Some(s) = self.vote_solicits.get(&h) {
+            s.source
+        } else {
+            return false;
+        };
+        self.tick_source.get(&(self.tick_of(h), source)) == Some(&h)
+    }
+
+    /// All the votes for the given proposal or solicit.
+    fn votes_for(&self, h: HashVal) -> impl Iterator<Item = &Vote> + '_ {
+        self.voters
+            .get(&h)
+            .into_iter()
+            .flat_map(|voters| voters.values())
+            .map(|vh| &self.votes[vh])
     }
 
     fn is_notarized(&self, h: HashVal) -> bool {
-        self.votes
-            .values()
-            .filter(|v| v.voting_for == h)
-            .map(|v| self.vote_map.get(&v.source).copied().unwrap_or_default())
-            .sum::<u64>()
-            > self.total_votes * 2 / 3
+        self.params.is_quorum(
+            self.tallies.get(&h).copied().unwrap_or_default(),
+            self.total_votes,
+        )
     }
 
     /// Produces the graphviz representation of the whole state.
@@ -417,12 +1199,15 @@ impl Core {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{leader::WeightedRandom, testutil::MemoryStorage};
     use itertools::Itertools;
+    use tap::Tap;
 
-    #[test]
-    fn normal_case() {
-        let players = (0..10).map(|_| tmelcrypt::ed25519_keygen().1).collect_vec();
-        let mut core = Core::new(
+    fn test_core(
+        players: &[Ed25519SK],
+        verify_proposal: impl Fn(&[u8]) -> bool + Send + Sync + 'static,
+    ) -> Core {
+        Core::new(
             0,
             players
                 .iter()
@@ -430,29 +1215,1020 @@ mod tests {
                 .map(|p| (p.to_public(), 1))
                 .collect_vec(),
             {
-                let players = players.clone();
+                let players = players.to_vec();
                 move |i| players[(i as usize) % players.len()].to_public()
             },
-        );
+            verify_proposal,
+            ProtocolParams::default(),
+        )
+    }
+
+    /// Drives every player through ticks until the core finalizes something.
+    fn run_to_finality(core: &mut Core, players: &[Ed25519SK]) -> Proposal {
         for tick in 0.. {
             if tick > 100 {
                 panic!("took too long to finalize");
             }
+            core.set_max_tick(tick + 1);
             for (pno, my_sk) in players.iter().copied().enumerate() {
                 core.insert_my_prop_or_solicit(tick, my_sk, || {
                     Bytes::copy_from_slice(format!("prop-{}", pno).as_bytes())
-                });
+                })
+                .unwrap();
             }
             for my_sk in players.iter().copied() {
                 if fastrand::f64() > 0.3 {
-                    core.insert_my_votes(my_sk)
+                    core.insert_my_votes(my_sk).unwrap()
                 }
             }
-            if let Some(_v) = core.get_finalized() {
+            if let Some(v) = core.get_finalized().unwrap() {
                 println!("FINALIZED!!!!");
-                break;
+                return v.clone();
             }
         }
+        unreachable!()
+    }
+
+    #[test]
+    fn normal_case() {
+        let players = (0..10).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        run_to_finality(&mut core, &players);
         println!("{}", core.debug_graphviz());
     }
+
+    #[test]
+    fn invalid_proposal_rejected() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |body| body != b"bad");
+        let bad = Proposal::new(0, 0, Bytes::from_static(b"bad"), players[0]);
+        assert_eq!(
+            core.apply_one_diff(DiffMessage::Proposal(bad.clone())),
+            Err(RejectReason::InvalidProposal)
+        );
+        // a vote for the rejected proposal has nothing to attach to
+        let vote = Vote::new(0, bad.chash(), players[1]);
+        assert_eq!(
+            core.apply_one_diff(DiffMessage::Vote(vote)),
+            Err(RejectReason::MissingParent(bad.chash()))
+        );
+        // rejection must not burn the leader's slot for this tick
+        let good = Proposal::new(0, 0, Bytes::from_static(b"good"), players[0]);
+        core.apply_one_diff(DiffMessage::Proposal(good)).unwrap();
+    }
+
+    #[test]
+    fn invalid_proposal_never_finalized() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let is_valid = |body: &[u8]| body != b"prop-0" && body != b"prop-1";
+        // a core that accepts everything finalizes something, possibly an invalid proposal
+        let mut permissive = test_core(&players, |_| true);
+        run_to_finality(&mut permissive, &players);
+        // a validating core fed the permissive core's entire state never takes in invalid proposals
+        let mut strict = test_core(&players, is_valid);
+        strict.set_max_tick(permissive.max_tick());
+        for dmsg in permissive.get_diff(&HashMap::new()) {
+            let _ = strict.apply_one_diff(dmsg);
+        }
+        assert!(strict.valid_proposals.values().all(|p| is_valid(&p.body)));
+        if let Some(prop) = strict.get_finalized().unwrap() {
+            assert!(is_valid(&prop.body));
+        }
+        // and running it to completion only ever finalizes valid proposals
+        let mut strict = test_core(&players, is_valid);
+        let finalized = run_to_finality(&mut strict, &players);
+        assert!(is_valid(&finalized.body));
+        assert!(strict.votes.values().all(|v| strict
+            .valid_proposals
+            .get(&v.voting_for)
+            .map(|p| is_valid(&p.body))
+            .unwrap_or(true)));
+    }
+
+    #[test]
+    fn conflicting_finalizations_are_fatal() {
+        let players = (0..10).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(100);
+        let vote_all = |core: &mut Core, hash: HashVal| {
+            for sk in players.iter().copied() {
+                core.insert_vote(Vote::new(0, hash, sk)).unwrap();
+            }
+        };
+        // everybody votes for two different proposals, then for two different chains growing from them
+        let a = Proposal::new(0, 0, Bytes::from_static(b"a"), players[0]);
+        let b = Proposal::new(0, 1, Bytes::from_static(b"b"), players[1]);
+        let (mut a_tip, mut b_tip) = (a.chash(), b.chash());
+        core.insert_proposal(a).unwrap();
+        core.insert_proposal(b).unwrap();
+        vote_all(&mut core, a_tip);
+        vote_all(&mut core, b_tip);
+        for tick in 2..5 {
+            let solicit = Solicit::new(0, tick, a_tip, players[tick as usize]);
+            a_tip = solicit.chash();
+            core.insert_solicit(solicit).unwrap();
+            vote_all(&mut core, a_tip);
+        }
+        assert!(core.get_finalized().unwrap().is_some());
+        for tick in 5..8 {
+            let solicit = Solicit::new(0, tick, b_tip, players[tick as usize]);
+            b_tip = solicit.chash();
+            core.insert_solicit(solicit).unwrap();
+            vote_all(&mut core, b_tip);
+        }
+        assert!(matches!(
+            core.get_finalized(),
+            Err(Fatal::ConflictingFinalizations(_, _))
+        ));
+    }
+
+    #[test]
+    fn mass_equivocation_is_fatal() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(100);
+        for tick in 0..2 {
+            let leader = players[tick as usize];
+            core.insert_proposal(Proposal::new(0, tick, Bytes::from_static(b"x"), leader))
+                .unwrap();
+            assert_eq!(
+                core.insert_proposal(Proposal::new(0, tick, Bytes::from_static(b"y"), leader)),
+                Err(RejectReason::Equivocation)
+            );
+            // re-sending the very same message is not equivocation
+            assert_eq!(
+                core.insert_proposal(Proposal::new(0, tick, Bytes::from_static(b"x"), leader)),
+                Err(RejectReason::Duplicate)
+            );
+            if tick == 0 {
+                // a single equivocator out of four does not break the 1/3 assumption
+                core.check_fatal().unwrap();
+            }
+        }
+        assert_eq!(
+            core.check_fatal(),
+            Err(Fatal::Equivocation(
+                players[..2]
+                    .iter()
+                    .map(|sk| sk.to_public())
+                    .sorted()
+                    .collect()
+            ))
+        );
+    }
+
+    #[test]
+    fn reject_reasons() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        let prop = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        core.apply_one_diff(DiffMessage::Proposal(prop.clone()))
+            .unwrap();
+        let reject = |core: &mut Core, dmsg| {
+            let reason = core.apply_one_diff(dmsg).unwrap_err();
+            (reason.is_benign(), reason)
+        };
+
+        // benign
+        assert_eq!(
+            reject(&mut core, DiffMessage::Proposal(prop.clone())),
+            (true, RejectReason::Duplicate)
+        );
+        let orphan = Solicit::new(0, 1, HashVal::random(), players[1]);
+        assert_eq!(
+            reject(&mut core, DiffMessage::Solicit(orphan.clone())),
+            (true, RejectReason::MissingParent(orphan.previous))
+        );
+        let future = Solicit::new(0, 5, prop.chash(), players[1]);
+        assert_eq!(
+            reject(&mut core, DiffMessage::Solicit(future)),
+            (
+                true,
+                RejectReason::FutureTick {
+                    tick: 5,
+                    max_tick: 1
+                }
+            )
+        );
+        let other_instance = Vote::new(1, prop.chash(), players[1]);
+        assert_eq!(
+            reject(&mut core, DiffMessage::Vote(other_instance)),
+            (true, RejectReason::WrongNonce(1))
+        );
+
+        // malicious
+        let mut forged = Vote::new(0, prop.chash(), players[1]);
+        forged.source = players[2].to_public();
+        assert_eq!(
+            reject(&mut core, DiffMessage::Vote(forged)),
+            (false, RejectReason::BadSignature)
+        );
+        core.set_max_tick(5);
+        let backwards = Solicit::new(0, 0, prop.chash(), players[0]);
+        assert_eq!(
+            reject(&mut core, DiffMessage::Solicit(backwards)),
+            (false, RejectReason::TickNotIncreasing)
+        );
+        let equivocation = Proposal::new(0, 0, Bytes::from_static(b"y"), players[0]);
+        assert_eq!(
+            reject(&mut core, DiffMessage::Proposal(equivocation)),
+            (false, RejectReason::Equivocation)
+        );
+    }
+
+    #[test]
+    fn notarized_twins() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(5);
+        // we were shown one proposal and voted for it, but everybody else was shown its twin
+        let shown = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        let twin = Proposal::new(0, 0, Bytes::from_static(b"y"), players[0]);
+        core.insert_proposal(shown.clone()).unwrap();
+        core.insert_my_votes(players[3]).unwrap();
+        assert_eq!(
+            core.insert_proposal(twin.clone()),
+            Err(RejectReason::Equivocation)
+        );
+        let next = Solicit::new(0, 1, twin.chash(), players[1]);
+        assert_eq!(
+            core.insert_solicit(next.clone()),
+            Err(RejectReason::MissingParent(twin.chash()))
+        );
+        for voter in &players[..2] {
+            core.insert_vote(Vote::new(0, twin.chash(), *voter))
+                .unwrap();
+        }
+        assert!(!core.valid_proposals.contains_key(&twin.chash()));
+        // once the twin is notarized, we follow chains building on it
+        core.insert_vote(Vote::new(0, twin.chash(), players[2]))
+            .unwrap();
+        assert!(core.is_notarized(twin.chash()));
+        assert_eq!(
+            core.tick_source[&(0, players[0].to_public())],
+            shown.chash()
+        );
+        core.insert_solicit(next.clone()).unwrap();
+        // but never vote for both
+        core.insert_my_votes(players[3]).unwrap();
+        assert!(core
+            .votes_for(twin.chash())
+            .all(|v| v.source != players[3].to_public()));
+        assert!(core
+            .votes_for(next.chash())
+            .any(|v| v.source == players[3].to_public()));
+
+        // and the twin makes it through snapshots
+        let mut sink = test_core(&players, |_| true);
+        sink.import_snapshot(&core.export_snapshot()).unwrap();
+        assert_eq!(sink.summary(), core.summary());
+    }
+
+    #[test]
+    fn twins_are_bounded() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        let twins = (0..MAX_TWINS + 3)
+            .map(|i| Proposal::new(0, 0, Bytes::from(format!("prop {}", i)), players[0]))
+            .collect_vec();
+        core.insert_proposal(twins[0].clone()).unwrap();
+        for twin in &twins[1..] {
+            assert_eq!(
+                core.insert_proposal(twin.clone()),
+                Err(RejectReason::Equivocation)
+            );
+        }
+        assert_eq!(core.twins.len(), MAX_TWINS);
+        // votes for twins that weren't kept have nothing to attach to
+        let dropped = twins.last().unwrap().chash();
+        assert_eq!(
+            core.insert_vote(Vote::new(0, dropped, players[1])),
+            Err(RejectReason::MissingParent(dropped))
+        );
+    }
+
+    #[test]
+    fn twins_are_journaled() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let storage = MemoryStorage::default();
+        let mut core = test_core(&players, |_| true);
+        core.set_journal(Arc::new(storage.clone()));
+        let shown = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        let twin = Proposal::new(0, 0, Bytes::from_static(b"y"), players[0]);
+        core.insert_proposal(shown).unwrap();
+        assert_eq!(
+            core.insert_proposal(twin.clone()),
+            Err(RejectReason::Equivocation)
+        );
+        // one vote short of notarizing the twin
+        for voter in &players[..4] {
+            core.insert_vote(Vote::new(0, twin.chash(), *voter))
+                .unwrap();
+        }
+
+        // replaying the journal, like Decider::recover does, gets the twin and its votes back
+        let mut recovered = test_core(&players, |_| true);
+        for entry in storage.replay().unwrap() {
+            if let JournalEntry::Message(msg) = entry {
+                match recovered.apply_one_diff(msg) {
+                    Ok(()) | Err(RejectReason::Equivocation) => {}
+                    Err(err) => panic!("{}", err),
+                }
+            }
+        }
+        let last = Vote::new(0, twin.chash(), players[4]);
+        core.insert_vote(last.clone()).unwrap();
+        recovered.insert_vote(last).unwrap();
+        assert!(recovered.is_notarized(twin.chash()));
+        assert_eq!(recovered.summary(), core.summary());
+        // nothing got journaled twice
+        assert_eq!(storage.replay().unwrap().len(), 7);
+    }
+
+    #[test]
+    fn equivocation_evidence() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        let prop = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        core.set_max_tick(3);
+        core.insert_proposal(prop.clone()).unwrap();
+        let solicit = Solicit::new(0, 2, prop.chash(), players[2]);
+        core.insert_solicit(solicit.clone()).unwrap();
+        assert_eq!(core.insert_solicit(solicit), Err(RejectReason::Duplicate));
+        assert!(core.equivocation_evidence().is_empty());
+        // a proposal and a solicit for the same tick conflict just as much as two proposals
+        let second = Proposal::new(0, 2, Bytes::from_static(b"y"), players[2]);
+        assert_eq!(
+            core.insert_proposal(second),
+            Err(RejectReason::Equivocation)
+        );
+
+        let evidence = core.equivocation_evidence();
+        assert_eq!(evidence.len(), 1);
+        let evidence = &evidence[0];
+        assert_eq!(evidence.offender(), players[2].to_public());
+        assert!(evidence.verify(0));
+        assert!(!evidence.verify(1));
+
+        // evidence survives serialization and is checked independently of any core
+        let decoded: EquivocationEvidence =
+            stdcode::deserialize(&stdcode::serialize(evidence).unwrap()).unwrap();
+        assert!(decoded.verify(0));
+
+        // the same message twice, or messages from different players or ticks, prove nothing
+        let same = EquivocationEvidence {
+            first: evidence.first.clone(),
+            second: evidence.first.clone(),
+        };
+        assert!(!same.verify(0));
+        let other_player = EquivocationEvidence {
+            first: evidence.first.clone(),
+            second: DiffMessage::Proposal(prop.clone()),
+        };
+        assert!(!other_player.verify(0));
+        let mut forged = evidence.clone();
+        if let DiffMessage::Proposal(p) = &mut forged.second {
+            p.body = Bytes::from_static(b"z");
+        }
+        assert!(!forged.verify(0));
+    }
+
+    #[test]
+    fn finality_certificate() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let weights: BTreeMap<Ed25519PK, u64> =
+            players.iter().map(|sk| (sk.to_public(), 1)).collect();
+        let mut core = test_core(&players, |_| true);
+        let finalized = run_to_finality(&mut core, &players);
+
+        let cert = core.finality_certificate().unwrap().unwrap();
+        assert_eq!(cert.proposal.chash(), finalized.chash());
+        assert!(cert.verify(&weights, 0));
+        assert!(!cert.verify(&weights, 1));
+        let decoded: FinalityCertificate =
+            stdcode::deserialize(&stdcode::serialize(&cert).unwrap()).unwrap();
+        assert!(decoded.verify(&weights, 0));
+
+        // a different validator set does not accept it
+        let strangers: BTreeMap<Ed25519PK, u64> = (0..7)
+            .map(|_| (Ed25519SK::generate().to_public(), 1))
+            .collect();
+        assert!(!cert.verify(&strangers, 0));
+
+        // nor does it survive losing its quorum, a broken chain, or a swapped proposal
+        let mut no_quorum = cert.clone();
+        no_quorum.votes.truncate(4);
+        assert!(!no_quorum.verify(&weights, 0));
+        let mut broken = cert.clone();
+        broken.solicits.remove(0);
+        assert!(!broken.verify(&weights, 0));
+        let mut swapped = cert;
+        swapped.proposal.body = Bytes::from_static(b"something else");
+        assert!(!swapped.verify(&weights, 0));
+    }
+
+    #[test]
+    fn protocol_params() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let weights: BTreeMap<Ed25519PK, u64> =
+            players.iter().map(|sk| (sk.to_public(), 1)).collect();
+        let weak = ProtocolParams {
+            threshold_numerator: 1,
+            threshold_denominator: 2,
+            depth: 2,
+        };
+        let mut cores = [
+            test_core(&players, |_| true),
+            Core::new(
+                0,
+                weights.clone(),
+                {
+                    let players = players.clone();
+                    move |i| players[(i as usize) % players.len()].to_public()
+                },
+                |_| true,
+                weak,
+            ),
+        ];
+        // a chain of two messages with consecutive ticks, each with 4 out of 7 votes
+        let prop = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        let solicit = Solicit::new(0, 1, prop.chash(), players[1]);
+        for core in cores.iter_mut() {
+            core.set_max_tick(1);
+            core.insert_proposal(prop.clone()).unwrap();
+            core.insert_solicit(solicit.clone()).unwrap();
+            for sk in players[..4].iter().copied() {
+                core.insert_vote(Vote::new(0, prop.chash(), sk)).unwrap();
+                core.insert_vote(Vote::new(0, solicit.chash(), sk)).unwrap();
+            }
+        }
+        let [strict, weak_core] = cores;
+        assert!(strict.get_lnc_tips().is_empty());
+        assert!(strict.get_finalized().unwrap().is_none());
+        assert_eq!(weak_core.get_lnc_tips(), vec![solicit.chash()]);
+        assert_eq!(
+            weak_core.get_finalized().unwrap().map(|p| p.chash()),
+            Some(prop.chash())
+        );
+
+        let cert = weak_core.finality_certificate().unwrap().unwrap();
+        assert!(cert.verify_with_params(&weights, 0, weak));
+        assert!(!cert.verify(&weights, 0));
+        assert!(!cert.verify_with_params(&weights, 0, ProtocolParams { depth: 0, ..weak }));
+    }
+
+    #[test]
+    fn huge_weights() {
+        for _ in 0..5 {
+            let players = (0..10).map(|_| Ed25519SK::generate()).collect_vec();
+            // mostly near the maximum, plus a few players with no weight at all
+            let weights: BTreeMap<Ed25519PK, u64> = players
+                .iter()
+                .enumerate()
+                .map(|(i, sk)| {
+                    let weight = if i < 2 {
+                        0
+                    } else {
+                        u64::MAX - fastrand::u64(..1 << 20)
+                    };
+                    (sk.to_public(), weight)
+                })
+                .collect();
+            let mut core = Core::new(
+                0,
+                weights.clone(),
+                WeightedRandom::new(fastrand::u128(..), weights.clone()),
+                |_| true,
+                ProtocolParams::default(),
+            );
+            run_to_finality(&mut core, &players);
+            let cert = core.finality_certificate().unwrap().unwrap();
+            assert!(cert.verify(&weights, 0));
+
+            // votes from the zero-weight players count for nothing
+            let zero_only = cert
+                .clone()
+                .tap_mut(|c| c.votes.retain(|v| weights[&v.source] == 0));
+            assert!(!zero_only.verify(&weights, 0));
+        }
+
+        // exactly a third of the weight equivocating is not yet fatal
+        let players = (0..3).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = Core::new(
+            0,
+            players.iter().map(|sk| (sk.to_public(), u64::MAX)),
+            {
+                let players = players.clone();
+                move |i| players[(i as usize) % players.len()].to_public()
+            },
+            |_| true,
+            ProtocolParams::default(),
+        );
+        core.set_max_tick(10);
+        for (tick, sk) in players.iter().copied().enumerate().take(2) {
+            for body in [&b"a"[..], &b"b"[..]] {
+                let _ = core.insert_proposal(Proposal::new(
+                    0,
+                    tick as u64,
+                    Bytes::copy_from_slice(body),
+                    sk,
+                ));
+            }
+            if tick == 0 {
+                core.check_fatal().unwrap();
+            }
+        }
+        assert!(matches!(core.check_fatal(), Err(Fatal::Equivocation(_))));
+    }
+
+    #[test]
+    fn outsiders_rejected() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        let prop = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        core.insert_proposal(prop.clone()).unwrap();
+        let summary = core.summary();
+
+        // an outsider with an unlimited supply of keys cannot make the victim store anything
+        for _ in 0..256 {
+            let outsider = Ed25519SK::generate();
+            let vote = Vote::new(0, prop.chash(), outsider);
+            assert_eq!(
+                core.apply_one_diff(DiffMessage::Vote(vote)),
+                Err(RejectReason::UnknownPlayer(outsider.to_public()))
+            );
+            let solicit = Solicit::new(0, 1, prop.chash(), outsider);
+            assert_eq!(
+                core.apply_one_diff(DiffMessage::Solicit(solicit)),
+                Err(RejectReason::UnknownPlayer(outsider.to_public()))
+            );
+        }
+        let outsider = Ed25519SK::generate();
+        let prop = Proposal::new(0, 1, Bytes::from_static(b"y"), outsider);
+        assert_eq!(
+            core.apply_one_diff(DiffMessage::Proposal(prop)),
+            Err(RejectReason::UnknownPlayer(outsider.to_public()))
+        );
+        assert!(!RejectReason::UnknownPlayer(outsider.to_public()).is_benign());
+        assert_eq!(core.votes.len(), 0);
+        assert_eq!(core.vote_solicits.len(), 0);
+        assert_eq!(core.valid_proposals.len(), 1);
+        assert_eq!(core.summary(), summary);
+    }
+
+    #[test]
+    fn only_leaders_author() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(10);
+        let body = || Bytes::from_static(b"x");
+
+        // validly signed, but not by the leader
+        let not_leader = Proposal::new(0, 0, body(), players[1]);
+        assert_eq!(
+            core.insert_proposal(not_leader),
+            Err(RejectReason::WrongLeader)
+        );
+        // claims to be from the leader, but signed by somebody else
+        let forged =
+            Proposal::new(0, 0, body(), players[1]).tap_mut(|p| p.source = players[0].to_public());
+        assert_eq!(
+            core.insert_proposal(forged),
+            Err(RejectReason::BadSignature)
+        );
+        // claims to be from a non-leader, and signed by the leader
+        let disowned =
+            Proposal::new(0, 0, body(), players[0]).tap_mut(|p| p.source = players[1].to_public());
+        assert_eq!(
+            core.insert_proposal(disowned),
+            Err(RejectReason::BadSignature)
+        );
+        // signed by the leader, but tampered with
+        let tampered = Proposal::new(0, 0, body(), players[0]).tap_mut(|p| p.tick = 4);
+        assert_eq!(
+            core.insert_proposal(tampered),
+            Err(RejectReason::BadSignature)
+        );
+
+        // the leader, with a VRF proof nobody asked for
+        let unasked = Proposal::unsigned(0, 0, body(), players[0].to_public())
+            .tap_mut(|p| p.vrf_proof = Bytes::from_static(b"proof"))
+            .signed(players[0]);
+        assert_eq!(
+            core.insert_proposal(unasked),
+            Err(RejectReason::WrongLeader)
+        );
+
+        // none of that burned the leader's slot
+        let prop = Proposal::new(0, 0, body(), players[0]);
+        core.insert_proposal(prop.clone()).unwrap();
+        assert!(core.equivocation_evidence().is_empty());
+
+        // same for solicits
+        let not_leader = Solicit::new(0, 1, prop.chash(), players[2]);
+        assert_eq!(
+            core.insert_solicit(not_leader),
+            Err(RejectReason::WrongLeader)
+        );
+        let forged = Solicit::new(0, 1, prop.chash(), players[2])
+            .tap_mut(|s| s.source = players[1].to_public());
+        assert_eq!(core.insert_solicit(forged), Err(RejectReason::BadSignature));
+        let disowned = Solicit::new(0, 1, prop.chash(), players[1])
+            .tap_mut(|s| s.source = players[2].to_public());
+        assert_eq!(
+            core.insert_solicit(disowned),
+            Err(RejectReason::BadSignature)
+        );
+        core.insert_solicit(Solicit::new(0, 1, prop.chash(), players[1]))
+            .unwrap();
+        assert!(core.equivocation_evidence().is_empty());
+
+        // and for messages arriving through diffs
+        let not_leader = Proposal::new(0, 2, body(), players[3]);
+        assert_eq!(
+            core.apply_one_diff(DiffMessage::Proposal(not_leader)),
+            Err(RejectReason::WrongLeader)
+        );
+    }
+
+    #[test]
+    fn other_nonces_are_benign() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let outsider = Ed25519SK::generate();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(10);
+        let body = || Bytes::from_static(b"x");
+
+        // validly signed for another instance, whose leaders and players we know nothing about
+        let messages = [
+            DiffMessage::Proposal(Proposal::new(1, 0, body(), players[1])),
+            DiffMessage::Proposal(Proposal::new(1, 0, body(), outsider)),
+            DiffMessage::Solicit(Solicit::new(1, 1, HashVal::default(), players[2])),
+            DiffMessage::Solicit(Solicit::new(1, 1, HashVal::default(), outsider)),
+            DiffMessage::Vote(Vote::new(1, HashVal::default(), outsider)),
+        ];
+        for msg in messages {
+            let err = core.apply_one_diff(msg).unwrap_err();
+            assert_eq!(err, RejectReason::WrongNonce(1));
+            assert!(err.is_benign());
+        }
+    }
+
+    #[test]
+    fn secret_leaders() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let election = VrfElection {
+            expected_leaders: 2,
+        };
+        let secret_core =
+            || test_core(&players, |_| true).tap_mut(|c| c.set_vrf_election(election));
+        let mut core = secret_core();
+        core.set_max_tick(1000);
+        let with_proof = |tick: u64, sk: Ed25519SK, proof: Vec<u8>| {
+            Proposal::unsigned(0, tick, Bytes::from_static(b"x"), sk.to_public())
+                .tap_mut(|p| p.vrf_proof = proof.into())
+                .signed(sk)
+        };
+        let eligible = |tick: u64, sk: Ed25519SK| {
+            let (proof, output) = vrf::prove(sk, &VrfElection::alpha(0, tick));
+            election.is_eligible(output, 1, 7).then_some(proof)
+        };
+        // find a tick with both a leader and a non-leader
+        let (tick, leader, proof, other) = (0..)
+            .find_map(|tick| {
+                let leader = players.iter().find(|sk| eligible(tick, **sk).is_some())?;
+                let other = players.iter().find(|sk| eligible(tick, **sk).is_none())?;
+                Some((tick, *leader, eligible(tick, *leader)?, *other))
+            })
+            .unwrap();
+        // no proof, somebody else's proof, and a proof for another tick are all rejected
+        assert_eq!(
+            core.insert_proposal(with_proof(tick, other, vec![])),
+            Err(RejectReason::WrongLeader)
+        );
+        assert_eq!(
+            core.insert_proposal(with_proof(tick, other, proof.clone())),
+            Err(RejectReason::WrongLeader)
+        );
+        assert_eq!(
+            core.insert_proposal(with_proof(tick + 1, leader, proof.clone())),
+            Err(RejectReason::WrongLeader)
+        );
+        core.insert_proposal(with_proof(tick, leader, proof))
+            .unwrap();
+
+        // the public schedule doesn't matter anymore, and nobody votes twice in a tick
+        let mut core = secret_core();
+        run_to_finality(&mut core, &players);
+        for voter in players.iter() {
+            let ticks = core
+                .votes
+                .values()
+                .filter(|v| v.source == voter.to_public())
+                .map(|v| core.tick_of(v.voting_for))
+                .collect_vec();
+            assert!(ticks.iter().all_unique());
+        }
+    }
+
+    /// Checks that the tally indices agree with naively scanning every vote, on a large instance. See `benches/tally.rs` for how much faster they are.
+    #[test]
+    fn indices_match_scanning() {
+        let players = (0..100).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut core = test_core(&players, |_| true);
+        core.set_max_tick(100);
+        let prop = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        let mut tip = prop.chash();
+        core.insert_proposal(prop).unwrap();
+        for tick in 1..30 {
+            for sk in players.iter().copied() {
+                core.insert_vote(Vote::new(0, tip, sk)).unwrap();
+            }
+            let solicit = Solicit::new(0, tick, tip, players[tick as usize % players.len()]);
+            tip = solicit.chash();
+            core.insert_solicit(solicit).unwrap();
+        }
+
+        let naive_is_notarized = |h: HashVal| {
+            core.params.is_quorum(
+                core.votes
+                    .values()
+                    .filter(|v| v.voting_for == h)
+                    .map(|v| core.vote_map[&v.source] as u128)
+                    .sum(),
+                core.total_votes,
+            )
+        };
+        let naive_lnc_tips = || {
+            let mut hash_and_len = core
+                .valid_proposals
+                .keys()
+                .chain(core.vote_solicits.keys())
+                .filter(|h| naive_is_notarized(**h))
+                .map(|h| (*h, core.chain_len[h]))
+                .collect_vec();
+            hash_and_len.sort_unstable_by_key(|a| std::cmp::Reverse(a.1));
+            let longest_len = hash_and_len.first().map(|s| s.1);
+            hash_and_len
+                .into_iter()
+                .take_while(|s| Some(s.1) == longest_len)
+                .map(|s| s.0)
+                .sorted()
+                .collect_vec()
+        };
+        let naive_tick_notarized = |tick: u64| {
+            core.tick_source
+                .iter()
+                .any(|((t, _), h)| *t == tick && naive_is_notarized(*h))
+        };
+        assert_eq!(naive_lnc_tips(), core.get_lnc_tips());
+        for tick in 0..31 {
+            assert_eq!(naive_tick_notarized(tick), core.tick_notarized(tick));
+        }
+
+        for tick in 0..31 {
+            let naive_voters: HashSet<Ed25519PK> = core
+                .votes
+                .values()
+                .filter(|v| core.tick_of(v.voting_for) == tick)
+                .map(|v| v.source)
+                .collect();
+            assert_eq!(
+                naive_voters,
+                core.tick_voters.get(&tick).cloned().unwrap_or_default()
+            );
+        }
+    }
+
+    #[test]
+    fn limited_diff() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut source = test_core(&players, |_| true);
+        run_to_finality(&mut source, &players);
+
+        for limit in [
+            DiffLimit {
+                max_bytes: usize::MAX,
+                max_msgs: 5,
+            },
+            DiffLimit {
+                max_bytes: 1000,
+                max_msgs: usize::MAX,
+            },
+            // smaller than any single message
+            DiffLimit {
+                max_bytes: 1,
+                max_msgs: usize::MAX,
+            },
+        ] {
+            let mut sink = test_core(&players, |_| true);
+            sink.set_max_tick(source.max_tick());
+            let mut rounds = 0;
+            let mut cursor = None;
+            loop {
+                rounds += 1;
+                assert!(rounds < 10000, "diffs not converging");
+                let diff = source.get_diff_limited(&sink.summary(), limit, cursor);
+                assert!(!diff.messages.is_empty());
+                assert!(diff.messages.len() <= limit.max_msgs);
+                let size: usize = diff.messages.iter().map(|m| m.stdcode().len()).sum();
+                assert!(size <= limit.max_bytes || diff.messages.len() == 1);
+                // causal order means every message applies cleanly
+                for dmsg in diff.messages {
+                    sink.apply_one_diff(dmsg).unwrap();
+                }
+                cursor = diff.next;
+                if cursor.is_none() {
+                    break;
+                }
+            }
+            assert!(rounds > 1);
+            assert_eq!(sink.summary(), source.summary());
+            assert!(source.get_diff(&sink.summary()).is_empty());
+            assert_eq!(
+                sink.get_finalized().unwrap().map(|p| p.chash()),
+                source.get_finalized().unwrap().map(|p| p.chash())
+            );
+        }
+    }
+
+    #[test]
+    fn compact_sync() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut source = test_core(&players, |_| true);
+        run_to_finality(&mut source, &players);
+        let mut sink = test_core(&players, |_| true);
+        sink.set_max_tick(source.max_tick());
+
+        let sync = |source: &Core, sink: &mut Core| {
+            let ticks = source.mismatched_ticks(&sink.compact_summary(2));
+            let partial = sink.summary_for_ticks(&ticks);
+            let diff = source.get_diff_for_ticks(&ticks, &partial, DiffLimit::unlimited(), None);
+            assert!(diff.next.is_none());
+            for dmsg in diff.messages.iter().cloned() {
+                // all votes for something are resent if any are missing
+                match sink.apply_one_diff(dmsg) {
+                    Ok(()) | Err(RejectReason::Duplicate) => {}
+                    Err(err) => panic!("{}", err),
+                }
+            }
+            (ticks, diff.messages)
+        };
+
+        // from scratch, everything is sent
+        let (_, msgs) = sync(&source, &mut sink);
+        assert_eq!(msgs.len(), source.get_diff(&HashMap::new()).len());
+        assert_eq!(sink.summary(), source.summary());
+        assert_eq!(sink.compact_summary(2), source.compact_summary(2));
+
+        // in steady state, the compact summary has a constant size, and nothing is sent
+        assert_eq!(sink.compact_summary(2).ticks.len(), 2);
+        assert!(sink.summary().len() > 2);
+        let (ticks, msgs) = sync(&source, &mut sink);
+        assert!(ticks.is_empty());
+        assert!(msgs.is_empty());
+
+        let only_votes_for = |msgs: &[DiffMessage], target: HashVal| {
+            msgs.iter()
+                .all(|m| matches!(m, DiffMessage::Vote(v) if v.voting_for == target))
+        };
+
+        // a single new message only involves its own tick, which is always past the horizon when it's the newest
+        let tick = source.tick_digests.keys().next_back().unwrap() + 1;
+        source.set_max_tick(tick);
+        let solicit = Solicit::new(
+            0,
+            tick,
+            source.get_lnc_tips()[0],
+            players[tick as usize % players.len()],
+        );
+        source.insert_solicit(solicit.clone()).unwrap();
+        let (ticks, msgs) = sync(&source, &mut sink);
+        assert_eq!(ticks, BTreeSet::from([tick]));
+        assert!(matches!(&msgs[..], [DiffMessage::Solicit(s)] if s.chash() == solicit.chash()));
+        source
+            .insert_vote(Vote::new(0, solicit.chash(), players[0]))
+            .unwrap();
+        let (ticks, msgs) = sync(&source, &mut sink);
+        assert_eq!(ticks, BTreeSet::from([tick]));
+        assert!(only_votes_for(&msgs, solicit.chash()));
+        assert_eq!(sink.summary(), source.summary());
+
+        // ...even if it's older than the horizon, though then all the older ticks are checked
+        let first = source.valid_proposals.keys().next().copied().unwrap();
+        if let Some(late_voter) = players
+            .iter()
+            .copied()
+            .find(|sk| source.votes_for(first).all(|v| v.source != sk.to_public()))
+        {
+            source.insert_vote(Vote::new(0, first, late_voter)).unwrap();
+            let (ticks, msgs) = sync(&source, &mut sink);
+            assert!(ticks.contains(&source.tick_of(first)));
+            assert!(only_votes_for(&msgs, first));
+            assert_eq!(sink.summary(), source.summary());
+        }
+    }
+
+    #[test]
+    fn snapshot_roundtrip() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut source = test_core(&players, |_| true);
+        let decision = run_to_finality(&mut source, &players);
+        // a bit of equivocation evidence, too
+        assert_eq!(
+            source.insert_proposal(Proposal::new(
+                0,
+                0,
+                Bytes::from_static(b"other"),
+                players[0]
+            )),
+            Err(RejectReason::Equivocation)
+        );
+        let snapshot = source.export_snapshot();
+
+        let mut sink = test_core(&players, |_| true);
+        sink.import_snapshot(&snapshot).unwrap();
+        assert_eq!(sink.summary(), source.summary());
+        assert_eq!(sink.tick_source, source.tick_source);
+        assert_eq!(
+            sink.get_finalized().unwrap().map(|p| p.chash()),
+            Some(decision.chash())
+        );
+        assert_eq!(sink.equivocation_evidence().len(), 1);
+        assert_eq!(sink.max_tick(), 1);
+        // importing again changes nothing
+        sink.import_snapshot(&snapshot).unwrap();
+        assert_eq!(sink.export_snapshot(), snapshot);
+
+        let mut bad_version = snapshot.to_vec();
+        bad_version[0] = 200;
+        assert_eq!(
+            sink.import_snapshot(&bad_version),
+            Err(SnapshotError::UnsupportedVersion(200))
+        );
+        assert_eq!(
+            sink.import_snapshot(&snapshot[..snapshot.len() / 2]),
+            Err(SnapshotError::Malformed)
+        );
+
+        // everything is checked against the weights of the importer, and nothing is imported on failure
+        let mut stranger = test_core(&players[1..], |_| true);
+        assert!(matches!(
+            stranger.import_snapshot(&snapshot),
+            Err(SnapshotError::Rejected(_, _))
+        ));
+        assert!(stranger.summary().is_empty());
+    }
+
+    #[test]
+    fn snapshot_with_other_twins() {
+        let players = (0..4).map(|_| Ed25519SK::generate()).collect_vec();
+        let shown = Proposal::new(0, 0, Bytes::from_static(b"x"), players[0]);
+        let twin = Proposal::new(0, 0, Bytes::from_static(b"y"), players[0]);
+        // the source saw the twin first, and everybody else voted for it
+        let mut source = test_core(&players, |_| true);
+        source.insert_proposal(twin.clone()).unwrap();
+        for voter in &players[1..] {
+            source
+                .insert_vote(Vote::new(0, twin.chash(), *voter))
+                .unwrap();
+        }
+        let mut sink = test_core(&players, |_| true);
+        sink.insert_proposal(shown.clone()).unwrap();
+        sink.import_snapshot(&source.export_snapshot()).unwrap();
+        assert!(sink.is_notarized(twin.chash()));
+        assert_eq!(
+            sink.tick_source[&(0, players[0].to_public())],
+            shown.chash()
+        );
+        assert_eq!(sink.equivocation_evidence().len(), 1);
+    }
+
+    #[test]
+    fn snapshot_journaled_on_success() {
+        let players = (0..7).map(|_| Ed25519SK::generate()).collect_vec();
+        let mut source = test_core(&players, |_| true);
+        run_to_finality(&mut source, &players);
+        let snapshot = source.export_snapshot();
+
+        // a snapshot whose messages are fine, but whose tick sources lie
+        let mut lying: Snapshot = stdcode::deserialize(&snapshot[1..]).unwrap();
+        lying.tick_sources[0].2 = HashVal::random();
+        let mut lying_bytes = vec![SNAPSHOT_VERSION];
+        lying_bytes.extend_from_slice(&lying.stdcode());
+
+        let storage = MemoryStorage::default();
+        let mut sink = test_core(&players, |_| true);
+        sink.set_journal(Arc::new(storage.clone()));
+        assert_eq!(
+            sink.import_snapshot(&lying_bytes),
+            Err(SnapshotError::InconsistentTickSources)
+        );
+        assert!(storage.replay().unwrap().is_empty());
+        sink.import_snapshot(&snapshot).unwrap();
+        assert_eq!(
+            storage.replay().unwrap().len(),
+            source.get_diff(&HashMap::new()).len()
+        );
+    }
 }
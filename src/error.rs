@@ -0,0 +1,150 @@
+use std::fmt::Display;
+
+use tmelcrypt::{Ed25519PK, HashVal};
+
+/// A fatal error. This indicates that the assumptions behind Streamlette no longer hold --- usually that more than 1/3 of the vote weight is byzantine --- or that some internal invariant has been broken.
+///
+/// Streamlette never panics on these conditions; it's up to the caller to decide whether to crash, alert an operator, or carry on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fatal {
+    /// Two different proposals were both finalized.
+    ConflictingFinalizations(HashVal, HashVal),
+    /// Players controlling more than 1/3 of the vote weight were caught equivocating.
+    Equivocation(Vec<Ed25519PK>),
+    /// Some internal invariant was broken.
+    Internal(String),
+    /// Our own message could not be written to, or our state could not be recovered from, the [crate::Storage].
+    Storage(String),
+}
+
+impl Display for Fatal {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Fatal::ConflictingFinalizations(a, b) => {
+                write!(f, "conflicting finalized proposals {} and {}", a, b)
+            }
+            Fatal::Equivocation(players) => write!(
+                f,
+                "more than 1/3 of vote weight equivocated ({} players)",
+                players.len()
+            ),
+            Fatal::Internal(s) => write!(f, "internal invariant broken: {}", s),
+            Fatal::Storage(s) => write!(f, "storage failure: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for Fatal {}
+
+/// The reason a message was rejected by [crate::Core::apply_one_diff].
+///
+/// Rejections fall into two categories. *Benign* rejections happen routinely even between honest players: duplicates, messages that arrive before what they build on, or messages from a different instance. The other rejections can only be caused by a misbehaving (or badly broken) peer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RejectReason {
+    /// We already have this exact message.
+    Duplicate,
+    /// The message builds on a proposal or solicit we don't have yet.
+    MissingParent(HashVal),
+    /// The message is for a tick we haven't reached yet.
+    FutureTick { tick: u64, max_tick: u64 },
+    /// The message belongs to a different instance of Streamlette.
+    WrongNonce(u128),
+    /// The message's signature does not verify.
+    BadSignature,
+    /// The message's source is not the leader for its tick.
+    WrongLeader,
+    /// The message's source is not a participant in this instance.
+    UnknownPlayer(Ed25519PK),
+    /// The proposal's body does not pass the validation function.
+    InvalidProposal,
+    /// The solicit's tick is not after the tick of the message it extends.
+    TickNotIncreasing,
+    /// The source already sent a different proposal or solicit for this tick.
+    Equivocation,
+    /// The message was fine, but we failed to write it to our [crate::Storage]. This is entirely our own fault.
+    Storage(String),
+}
+
+impl RejectReason {
+    /// Whether this rejection can happen between honest players. A non-benign rejection means that somebody misbehaved, but not necessarily the author: a [RejectReason::BadSignature] proves nothing about the claimed author, since anybody can corrupt a message on its way, while the other non-benign reasons prove that the author signed something it shouldn't have.
+    pub fn is_benign(&self) -> bool {
+        matches!(
+            self,
+            RejectReason::Duplicate
+                | RejectReason::MissingParent(_)
+                | RejectReason::FutureTick { .. }
+                | RejectReason::WrongNonce(_)
+                | RejectReason::Storage(_)
+        )
+    }
+
+    /// Whether the same message might be accepted if it is retried later, once we've caught up.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            RejectReason::MissingParent(_)
+                | RejectReason::FutureTick { .. }
+                | RejectReason::Storage(_)
+        )
+    }
+}
+
+impl Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::Duplicate => write!(f, "duplicate message"),
+            RejectReason::MissingParent(h) => write!(f, "missing parent {}", h),
+            RejectReason::FutureTick { tick, max_tick } => {
+                write!(f, "tick {} > max tick {}", tick, max_tick)
+            }
+            RejectReason::WrongNonce(n) => write!(f, "wrong nonce {}", n),
+            RejectReason::BadSignature => write!(f, "bad signature"),
+            RejectReason::WrongLeader => write!(f, "source is not the leader for this tick"),
+            RejectReason::UnknownPlayer(pk) => write!(f, "unknown player {}", pk),
+            RejectReason::InvalidProposal => write!(f, "proposal body failed validation"),
+            RejectReason::TickNotIncreasing => {
+                write!(f, "tick of vote solicit cannot go backwards in time")
+            }
+            RejectReason::Equivocation => {
+                write!(f, "this player already sent something else for this tick")
+            }
+            RejectReason::Storage(s) => write!(f, "could not journal message: {}", s),
+        }
+    }
+}
+
+impl std::error::Error for RejectReason {}
+
+/// The reason a snapshot was refused by [crate::Core::import_snapshot].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SnapshotError {
+    /// The snapshot could not be decoded.
+    Malformed,
+    /// The snapshot was produced by a version of Streamlette we don't understand.
+    UnsupportedVersion(u8),
+    /// The snapshot belongs to a different instance of Streamlette.
+    WrongNonce(u128),
+    /// A message in the snapshot was rejected.
+    Rejected(HashVal, RejectReason),
+    /// A piece of equivocation evidence in the snapshot does not verify.
+    BadEvidence,
+    /// The snapshot's tick sources don't match its proposals and solicits.
+    InconsistentTickSources,
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Malformed => write!(f, "malformed snapshot"),
+            SnapshotError::UnsupportedVersion(v) => write!(f, "unsupported snapshot version {}", v),
+            SnapshotError::WrongNonce(n) => write!(f, "snapshot has wrong nonce {}", n),
+            SnapshotError::Rejected(h, reason) => write!(f, "message {} rejected: {}", h, reason),
+            SnapshotError::BadEvidence => write!(f, "equivocation evidence does not verify"),
+            SnapshotError::InconsistentTickSources => {
+                write!(f, "tick sources do not match the messages")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
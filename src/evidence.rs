@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use tmelcrypt::{Ed25519PK, HashVal};
+
+use crate::{core::DiffMessage, msg::Message};
+
+/// Proof that a player equivocated: two distinct, validly signed proposals or solicits from the same player, for the same instance and tick.
+///
+/// Evidence can be verified without any other context than the instance's nonce, so it can be handed to e.g. a staking layer to slash the offender.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct EquivocationEvidence {
+    pub first: DiffMessage,
+    pub second: DiffMessage,
+}
+
+impl EquivocationEvidence {
+    /// The player who equivocated. Only meaningful if [EquivocationEvidence::verify] returns true.
+    pub fn offender(&self) -> Ed25519PK {
+        match &self.first {
+            DiffMessage::Proposal(p) => p.source,
+            DiffMessage::Solicit(s) => s.source,
+            DiffMessage::Vote(v) => v.source,
+        }
+    }
+
+    /// Checks that this evidence really does prove that [EquivocationEvidence::offender] equivocated in the instance with the given nonce.
+    pub fn verify(&self, nonce: u128) -> bool {
+        match (slot_of(&self.first), slot_of(&self.second)) {
+            (Some(first), Some(second)) => {
+                first.0 == nonce
+                    && first.0 == second.0
+                    && first.1 == second.1
+                    && first.2 == second.2
+                    && first.3 != second.3
+                    && signature_ok(&self.first)
+                    && signature_ok(&self.second)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// The (nonce, tick, source, hash) of a proposal or solicit. Votes don't occupy a tick, so they can't be equivocated.
+fn slot_of(msg: &DiffMessage) -> Option<(u128, u64, Ed25519PK, HashVal)> {
+    match msg {
+        DiffMessage::Proposal(p) => Some((p.nonce, p.tick, p.source, p.chash())),
+        DiffMessage::Solicit(s) => Some((s.nonce, s.tick, s.source, s.chash())),
+        DiffMessage::Vote(_) => None,
+    }
+}
+
+fn signature_ok(msg: &DiffMessage) -> bool {
+    match msg {
+        DiffMessage::Proposal(p) => p.verify_sig(),
+        DiffMessage::Solicit(s) => s.verify_sig(),
+        DiffMessage::Vote(v) => v.verify_sig(),
+    }
+}
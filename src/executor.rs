@@ -0,0 +1,168 @@
+use std::{
+    collections::BTreeSet,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Wake, Waker},
+    time::Duration,
+};
+
+use crate::timer::MockClock;
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+/// A single-threaded executor running on simulated time, for reproducible simulations of many Deciders at once.
+///
+/// Tasks are polled in the order they were spawned, and the [MockClock] only moves forward once every task is waiting on it, straight to the next sleep that finishes. Give every Decider the executor's clock through [crate::DeciderConfig::timer], and a whole run, including timeouts in [crate::Decider::tick_to_end], depends only on what the tasks do, not on the speed of the machine.
+///
+/// Tasks must only wait on the clock or on each other. A task that never sleeps, such as one busy-looping with `yield_now`, stops time from ever moving.
+pub struct VirtualExecutor {
+    clock: MockClock,
+    tasks: Vec<Option<Task>>,
+    ready: Arc<Mutex<BTreeSet<usize>>>,
+}
+
+impl VirtualExecutor {
+    /// Creates an executor with a fresh clock.
+    pub fn new() -> Self {
+        Self {
+            clock: MockClock::new(),
+            tasks: vec![],
+            ready: Default::default(),
+        }
+    }
+
+    /// Returns the clock of the executor. Clones share the same time.
+    pub fn clock(&self) -> MockClock {
+        self.clock.clone()
+    }
+
+    /// Adds a task, to be first polled on the next call to [VirtualExecutor::run_until].
+    pub fn spawn(&mut self, task: impl Future<Output = ()> + 'static) {
+        self.ready.lock().unwrap().insert(self.tasks.len());
+        self.tasks.push(Some(Box::pin(task)));
+    }
+
+    /// Runs the tasks until `done` returns true, which is checked whenever time is about to move forward. Returns false instead if more than `limit` of simulated time passes in total, or if every task is stuck or finished without `done` returning true.
+    pub fn run_until(&mut self, limit: Duration, mut done: impl FnMut() -> bool) -> bool {
+        loop {
+            while let Some(id) = self.pop_ready() {
+                let waker = Waker::from(Arc::new(TaskWaker {
+                    id,
+                    ready: self.ready.clone(),
+                }));
+                if let Some(task) = &mut self.tasks[id] {
+                    if task
+                        .as_mut()
+                        .poll(&mut Context::from_waker(&waker))
+                        .is_ready()
+                    {
+                        self.tasks[id] = None;
+                    }
+                }
+            }
+            if done() {
+                return true;
+            }
+            match self.clock.next_wakeup() {
+                Some(wait) if self.clock.elapsed() + wait <= limit => self.clock.advance(wait),
+                _ => return false,
+            }
+        }
+    }
+
+    fn pop_ready(&self) -> Option<usize> {
+        self.ready.lock().unwrap().pop_first()
+    }
+}
+
+impl Default for VirtualExecutor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<BTreeSet<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready.lock().unwrap().insert(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::BTreeMap, rc::Rc};
+
+    use bytes::Bytes;
+    use tmelcrypt::Ed25519SK;
+
+    use super::*;
+    use crate::{testutil::Player, Decider, Timer};
+
+    #[test]
+    fn runs_on_simulated_time() {
+        let mut executor = VirtualExecutor::new();
+        let clock = executor.clock();
+        let log = Rc::new(RefCell::new(vec![]));
+        for (name, secs) in [("slow", 30), ("fast", 10)] {
+            let clock = clock.clone();
+            let log = log.clone();
+            executor.spawn(async move {
+                clock.sleep(Duration::from_secs(secs)).await;
+                log.borrow_mut().push((name, clock.elapsed()));
+            });
+        }
+        assert!(!executor.run_until(Duration::from_secs(20), || log.borrow().len() == 2));
+        assert!(executor.run_until(Duration::from_secs(60), || log.borrow().len() == 2));
+        assert_eq!(
+            *log.borrow(),
+            vec![
+                ("fast", Duration::from_secs(10)),
+                ("slow", Duration::from_secs(30))
+            ]
+        );
+    }
+
+    /// Runs a network of deciders to the end, returning what each decided and when.
+    fn simulate(keys: &[Ed25519SK]) -> Vec<(Bytes, Duration)> {
+        let mut executor = VirtualExecutor::new();
+        let cores = Arc::new(Mutex::new(BTreeMap::new()));
+        let decisions = Rc::new(RefCell::new(BTreeMap::new()));
+        for index in 0..keys.len() {
+            let mut decider = Decider::new(Player {
+                keys: keys.to_vec(),
+                index,
+                cores: cores.clone(),
+                clock: executor.clock(),
+            });
+            let clock = executor.clock();
+            let decisions = decisions.clone();
+            executor.spawn(async move {
+                let decision = decider.tick_to_end().await.unwrap();
+                decisions
+                    .borrow_mut()
+                    .insert(index, (decision, clock.elapsed()));
+                // keep helping the others
+                decider.sync_state(None).await;
+            });
+        }
+        assert!(
+            executor.run_until(Duration::from_secs(3600), || decisions.borrow().len()
+                == keys.len())
+        );
+        let decisions = decisions.borrow();
+        decisions.values().cloned().collect()
+    }
+
+    #[test]
+    fn reproducible_simulation() {
+        let keys: Vec<Ed25519SK> = (0..4).map(|_| Ed25519SK::generate()).collect();
+        let first = simulate(&keys);
+        assert!(first.iter().all(|(decision, _)| decision == &first[0].0));
+        assert_eq!(first, simulate(&keys));
+    }
+}
@@ -0,0 +1,218 @@
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+use tmelcrypt::HashVal;
+
+/// The kind of message a [SigningGuard] is asked to approve.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum MessageKind {
+    Proposal,
+    Solicit,
+    Vote,
+}
+
+impl MessageKind {
+    fn name(self) -> &'static str {
+        match self {
+            MessageKind::Proposal => "proposal",
+            MessageKind::Solicit => "solicit",
+            MessageKind::Vote => "vote",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "proposal" => Some(MessageKind::Proposal),
+            "solicit" => Some(MessageKind::Solicit),
+            "vote" => Some(MessageKind::Vote),
+            _ => None,
+        }
+    }
+}
+
+/// A last line of defense against signing two conflicting messages, for example because two copies of the same player are running at once. Consulted right before anything is signed with our secret key.
+pub trait SigningGuard: Send + Sync + 'static {
+    /// Returns whether it's okay to sign the message of the given kind with the given hash, for the given instance and tick. Votes count towards the tick of what they vote for.
+    ///
+    /// Once approved, a message must be remembered, and no conflicting message for the same instance and tick may ever be approved afterwards. Approving the exact same message again is fine.
+    fn approve(&self, nonce: u128, tick: u64, kind: MessageKind, hash: HashVal) -> bool;
+}
+
+/// A [SigningGuard] that records everything it approves in a local file, similar to a validator's slashing-protection database.
+///
+/// The file is exclusively locked while the guard exists, so that two processes sharing the file can't both sign. Every record is a line of the form `nonce tick kind hash`.
+pub struct FileSigningGuard {
+    inner: Mutex<GuardInner>,
+}
+
+struct GuardInner {
+    file: File,
+    /// Keyed by nonce, tick, and whether it's a vote. A leader sends *either* a proposal or a solicit for its tick, so those two kinds share a slot.
+    signed: BTreeMap<(u128, u64, bool), (MessageKind, HashVal)>,
+}
+
+impl FileSigningGuard {
+    /// Opens the guard at the given path, creating it if it doesn't exist. Fails if another guard holds the file.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        file.try_lock()?;
+        file.seek(SeekFrom::Start(0))?;
+        let mut contents = String::new();
+        file.read_to_string(&mut contents)?;
+        let mut signed = BTreeMap::new();
+        let mut good_len = 0;
+        // a line without its newline was torn by a crash, and the signature never left the process
+        for line in contents.split_inclusive('\n').filter(|l| l.ends_with('\n')) {
+            let (nonce, tick, kind, hash) = parse_record(line.trim_end()).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("bad signing guard record {:?}", line),
+                )
+            })?;
+            signed.insert((nonce, tick, kind == MessageKind::Vote), (kind, hash));
+            good_len += line.len();
+        }
+        if good_len < contents.len() {
+            file.set_len(good_len as u64)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            inner: Mutex::new(GuardInner { file, signed }),
+        })
+    }
+}
+
+impl SigningGuard for FileSigningGuard {
+    fn approve(&self, nonce: u128, tick: u64, kind: MessageKind, hash: HashVal) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let key = (nonce, tick, kind == MessageKind::Vote);
+        if let Some(&(existing_kind, existing)) = inner.signed.get(&key) {
+            if existing_kind != kind || existing != hash {
+                log::warn!(
+                    "refusing to sign {} {} at tick {}, since we already signed {} {}",
+                    kind.name(),
+                    hash,
+                    tick,
+                    existing_kind.name(),
+                    existing
+                );
+                return false;
+            }
+            return true;
+        }
+        let record = format!("{} {} {} {}\n", nonce, tick, kind.name(), hash);
+        // if we can't remember it, we must not sign it
+        if let Err(err) = inner
+            .file
+            .write_all(record.as_bytes())
+            .and_then(|_| inner.file.sync_data())
+        {
+            log::warn!("could not record signature in signing guard: {}", err);
+            return false;
+        }
+        inner.signed.insert(key, (kind, hash));
+        true
+    }
+}
+
+fn parse_record(line: &str) -> Option<(u128, u64, MessageKind, HashVal)> {
+    let mut fields = line.split(' ');
+    let toret = (
+        fields.next()?.parse().ok()?,
+        fields.next()?.parse().ok()?,
+        MessageKind::from_name(fields.next()?)?,
+        fields.next()?.parse().ok()?,
+    );
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(toret)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use bytes::Bytes;
+    use tmelcrypt::Ed25519SK;
+
+    use super::*;
+    use crate::{testutil::temp_path, Core, DiffMessage};
+
+    #[test]
+    fn file_guard() {
+        let path = temp_path("guard");
+        let (a, b) = (HashVal::random(), HashVal::random());
+        let guard = FileSigningGuard::open(&path).unwrap();
+        assert!(FileSigningGuard::open(&path).is_err());
+        assert!(guard.approve(0, 1, MessageKind::Proposal, a));
+        assert!(guard.approve(0, 1, MessageKind::Proposal, a));
+        assert!(!guard.approve(0, 1, MessageKind::Proposal, b));
+        assert!(!guard.approve(0, 1, MessageKind::Solicit, a));
+        assert!(guard.approve(0, 1, MessageKind::Vote, b));
+        assert!(guard.approve(1, 1, MessageKind::Solicit, b));
+        drop(guard);
+
+        let guard = FileSigningGuard::open(&path).unwrap();
+        assert!(guard.approve(0, 1, MessageKind::Proposal, a));
+        assert!(!guard.approve(0, 1, MessageKind::Proposal, b));
+        assert!(!guard.approve(0, 1, MessageKind::Vote, a));
+        assert!(!guard.approve(1, 1, MessageKind::Solicit, a));
+        drop(guard);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn two_copies_cannot_equivocate() {
+        let path = temp_path("guard");
+        let sk = Ed25519SK::generate();
+        let guard: Arc<dyn SigningGuard> = Arc::new(FileSigningGuard::open(&path).unwrap());
+        let copy = || {
+            let mut core = Core::new(
+                0,
+                [(sk.to_public(), 1)],
+                move |_| sk.to_public(),
+                |_| true,
+                Default::default(),
+            );
+            core.set_signing_guard(guard.clone());
+            core
+        };
+        let mut first = copy();
+        let mut second = copy();
+        first
+            .insert_my_prop_or_solicit(0, sk, || Bytes::from_static(b"hello"))
+            .unwrap();
+        second
+            .insert_my_prop_or_solicit(0, sk, || Bytes::from_static(b"world"))
+            .unwrap();
+        assert_eq!(first.get_diff(&Default::default()).len(), 1);
+        assert!(second.get_diff(&Default::default()).is_empty());
+
+        // the second copy may still vote for what the first one proposed
+        for msg in first.get_diff(&Default::default()) {
+            second.apply_one_diff(msg).unwrap();
+        }
+        first.insert_my_votes(sk).unwrap();
+        second.insert_my_votes(sk).unwrap();
+        let votes = |core: &Core| {
+            core.get_diff(&Default::default())
+                .into_iter()
+                .filter(|msg| matches!(msg, DiffMessage::Vote(_)))
+                .count()
+        };
+        assert_eq!(votes(&first), 1);
+        assert_eq!(votes(&second), 1);
+        drop(guard);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,300 @@
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex},
+};
+
+use arrayref::array_ref;
+use tmelcrypt::Ed25519PK;
+
+/// Decides who the leader is for every tick. The leader is the only player allowed to send a proposal or solicit for its tick.
+///
+/// Every player must use the same schedule, and it must return the same value every time for the same tick.
+pub trait LeaderSchedule: Send + Sync + 'static {
+    /// Returns the leader for the given tick.
+    fn leader(&self, tick: u64) -> Ed25519PK;
+}
+
+impl<F: Fn(u64) -> Ed25519PK + Send + Sync + 'static> LeaderSchedule for F {
+    fn leader(&self, tick: u64) -> Ed25519PK {
+        self(tick)
+    }
+}
+
+impl<T: LeaderSchedule + ?Sized> LeaderSchedule for Arc<T> {
+    fn leader(&self, tick: u64) -> Ed25519PK {
+        self.as_ref().leader(tick)
+    }
+}
+
+/// A deterministic pseudorandom generator keyed by a seed, used by the schedules to make their choices.
+struct SeededRng {
+    state: u128,
+}
+
+impl SeededRng {
+    fn new(seed: u128, stream: u64) -> Self {
+        Self {
+            state: seed.wrapping_add(stream as u128),
+        }
+    }
+
+    /// Picks a number in `0..bound` uniformly at random. The bound must not be zero.
+    fn below(&mut self, bound: u128) -> u128 {
+        debug_assert!(bound > 0);
+        // keep only as many random bits as the bound has, and retry until we're below it
+        let mut point = u128::MAX;
+        while point >= bound {
+            let v = tmelcrypt::hash_single(self.state.to_be_bytes());
+            self.state = u128::from_be_bytes(*array_ref![v, 0, 16]);
+            point = self.state >> bound.leading_zeros();
+        }
+        point
+    }
+}
+
+/// Picks a player according to its weight, given a point in `0..total weight`. We add the weights together until we exceed the point; the player we're at when that happens is the selected one.
+fn pick_weighted<'a>(
+    weights: impl IntoIterator<Item = (&'a Ed25519PK, &'a u64)>,
+    point: u128,
+) -> Ed25519PK {
+    let mut sum = 0u128;
+    for (&pk, &weight) in weights {
+        sum += weight as u128;
+        if sum > point {
+            return pk;
+        }
+    }
+    unreachable!()
+}
+
+/// Every tick, picks a leader independently at random, with probability proportional to its weight. This is the default.
+pub struct WeightedRandom {
+    seed: u128,
+    weights: BTreeMap<Ed25519PK, u64>,
+    total_votes: u128,
+}
+
+impl WeightedRandom {
+    /// Creates a schedule from a random seed and the vote weights.
+    ///
+    /// Panics if no player has a nonzero weight.
+    pub fn new(seed: u128, weights: BTreeMap<Ed25519PK, u64>) -> Self {
+        let total_votes = weights.values().map(|&w| w as u128).sum();
+        assert!(total_votes > 0, "no player has a nonzero weight");
+        Self {
+            seed,
+            weights,
+            total_votes,
+        }
+    }
+}
+
+impl LeaderSchedule for WeightedRandom {
+    fn leader(&self, tick: u64) -> Ed25519PK {
+        // we first randomly and fairly pick a number between 0 and total_votes.
+        let random_point = SeededRng::new(self.seed, tick).below(self.total_votes);
+        // using that random number, we then pick a player according to its weight.
+        pick_weighted(&self.weights, random_point)
+    }
+}
+
+/// Takes turns between every player with a nonzero weight, in order of their public keys, regardless of weight. Completely predictable, which is handy for test networks.
+pub struct RoundRobin {
+    players: Vec<Ed25519PK>,
+}
+
+impl RoundRobin {
+    /// Creates a schedule from the vote weights.
+    ///
+    /// Panics if no player has a nonzero weight.
+    pub fn new(weights: &BTreeMap<Ed25519PK, u64>) -> Self {
+        let players: Vec<Ed25519PK> = weights
+            .iter()
+            .filter(|(_, weight)| **weight > 0)
+            .map(|(pk, _)| *pk)
+            .collect();
+        assert!(!players.is_empty(), "no player has a nonzero weight");
+        Self { players }
+    }
+}
+
+impl LeaderSchedule for RoundRobin {
+    fn leader(&self, tick: u64) -> Ed25519PK {
+        self.players[(tick % self.players.len() as u64) as usize]
+    }
+}
+
+/// Divides ticks into rounds, in which every player with a nonzero weight is leader exactly once. The order within each round is random, picking the leader of each tick from those who haven't led yet in the round with probability proportional to their weights.
+///
+/// Unlike [WeightedRandom], nobody can be unlucky enough to wait more than two rounds to lead.
+pub struct WeightedRoundRobin {
+    seed: u128,
+    weights: BTreeMap<Ed25519PK, u64>,
+    /// The order of the most recently asked-about rounds, since working one out takes time quadratic in the number of players.
+    rounds: Mutex<BTreeMap<u64, Arc<Vec<Ed25519PK>>>>,
+}
+
+/// How many rounds [WeightedRoundRobin] remembers the order of. Messages for the ticks around a round boundary come in interleaved, so this must be more than one.
+const CACHED_ROUNDS: usize = 4;
+
+impl WeightedRoundRobin {
+    /// Creates a schedule from a random seed and the vote weights.
+    ///
+    /// Panics if no player has a nonzero weight.
+    pub fn new(seed: u128, weights: BTreeMap<Ed25519PK, u64>) -> Self {
+        let weights: BTreeMap<Ed25519PK, u64> =
+            weights.into_iter().filter(|(_, w)| *w > 0).collect();
+        assert!(!weights.is_empty(), "no player has a nonzero weight");
+        Self {
+            seed,
+            weights,
+            rounds: Default::default(),
+        }
+    }
+
+    /// Works out who leads in which order in the given round.
+    fn order(&self, round: u64) -> Vec<Ed25519PK> {
+        let mut rng = SeededRng::new(self.seed, round);
+        let mut remaining = self.weights.clone();
+        let mut remaining_votes: u128 = remaining.values().map(|&w| w as u128).sum();
+        let mut order = Vec::with_capacity(remaining.len());
+        while !remaining.is_empty() {
+            let pk = pick_weighted(&remaining, rng.below(remaining_votes));
+            remaining_votes -= remaining.remove(&pk).unwrap() as u128;
+            order.push(pk);
+        }
+        order
+    }
+}
+
+impl LeaderSchedule for WeightedRoundRobin {
+    fn leader(&self, tick: u64) -> Ed25519PK {
+        let round_len = self.weights.len() as u64;
+        let (round, position) = (tick / round_len, tick % round_len);
+        let cached = self.rounds.lock().unwrap().get(&round).cloned();
+        let order = cached.unwrap_or_else(|| {
+            let order = Arc::new(self.order(round));
+            let mut rounds = self.rounds.lock().unwrap();
+            rounds.insert(round, order.clone());
+            if rounds.len() > CACHED_ROUNDS {
+                // forget whichever round is the furthest from this one
+                let furthest = *rounds
+                    .keys()
+                    .max_by_key(|other| other.abs_diff(round))
+                    .unwrap();
+                rounds.remove(&furthest);
+            }
+            order
+        });
+        order[position as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use tmelcrypt::Ed25519SK;
+
+    use super::*;
+
+    fn weights() -> BTreeMap<Ed25519PK, u64> {
+        (0..5)
+            .map(|i| (Ed25519SK::generate().to_public(), i * 10))
+            .collect()
+    }
+
+    #[test]
+    fn round_robin() {
+        let weights = weights();
+        let schedule = RoundRobin::new(&weights);
+        let leaders = (0..8).map(|tick| schedule.leader(tick)).collect::<Vec<_>>();
+        let players = weights
+            .iter()
+            .filter(|(_, weight)| **weight > 0)
+            .map(|(pk, _)| *pk)
+            .collect::<Vec<_>>();
+        assert_eq!(leaders[..4], players[..]);
+        assert_eq!(leaders[4..], players[..]);
+    }
+
+    #[test]
+    fn weighted_round_robin() {
+        let weights = weights();
+        let schedule = WeightedRoundRobin::new(42, weights.clone());
+        let mut first_counts = BTreeMap::new();
+        for round in 0..500 {
+            let leaders = (round * 4..round * 4 + 4)
+                .map(|tick| schedule.leader(tick))
+                .collect::<Vec<_>>();
+            // everybody with a nonzero weight leads exactly once per round
+            let unique = leaders.iter().copied().collect::<BTreeSet<_>>();
+            assert_eq!(unique.len(), 4);
+            assert!(unique.iter().all(|pk| weights[pk] > 0));
+            *first_counts.entry(weights[&leaders[0]]).or_insert(0) += 1;
+        }
+        // heavier players tend to go first
+        assert!(first_counts[&40] > first_counts[&10]);
+        assert_eq!(
+            schedule.leader(1234),
+            WeightedRoundRobin::new(42, weights.clone()).leader(1234)
+        );
+        // whatever order the rounds are asked about in, and whatever is cached
+        let fresh = WeightedRoundRobin::new(42, weights);
+        for tick in (0..200).rev().chain([3, 150, 7, 1999, 4, 1998]) {
+            assert_eq!(
+                schedule.leader(tick),
+                fresh.order(tick / 4)[tick as usize % 4]
+            );
+        }
+    }
+
+    #[test]
+    fn weighted_random() {
+        let weights = weights();
+        let schedule = WeightedRandom::new(42, weights.clone());
+        let mut counts = BTreeMap::new();
+        for tick in 0..5000 {
+            *counts.entry(weights[&schedule.leader(tick)]).or_insert(0) += 1;
+        }
+        assert!(!counts.contains_key(&0));
+        assert!(counts[&40] > counts[&30]);
+        assert!(counts[&30] > counts[&10]);
+    }
+
+    #[test]
+    fn extreme_weights() {
+        for _ in 0..50 {
+            // weights at both extremes, with at least one nonzero
+            let mut weights: BTreeMap<Ed25519PK, u64> = (0..fastrand::usize(1..20))
+                .map(|_| {
+                    let weight = match fastrand::u8(..4) {
+                        0 => 0,
+                        1 => u64::MAX,
+                        2 => u64::MAX - fastrand::u64(..1000),
+                        _ => fastrand::u64(..),
+                    };
+                    (Ed25519SK::generate().to_public(), weight)
+                })
+                .collect();
+            weights.insert(Ed25519SK::generate().to_public(), u64::MAX);
+            let seed = fastrand::u128(..);
+            let schedules: Vec<Box<dyn LeaderSchedule>> = vec![
+                Box::new(WeightedRandom::new(seed, weights.clone())),
+                Box::new(RoundRobin::new(&weights)),
+                Box::new(WeightedRoundRobin::new(seed, weights.clone())),
+            ];
+            for schedule in schedules {
+                for tick in 0..100 {
+                    assert!(weights[&schedule.leader(tick)] > 0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn no_weight_at_all() {
+        WeightedRandom::new(0, [(Ed25519SK::generate().to_public(), 0)].into());
+    }
+}
@@ -1,5 +1,38 @@
+mod certificate;
 mod consensus;
 mod core;
+mod error;
+mod evidence;
+mod executor;
+mod guard;
+mod leader;
 mod msg;
-pub use crate::core::{Core, DiffMessage};
+#[cfg(feature = "mutation-testing")]
+mod mutation;
+mod params;
+mod storage;
+#[cfg(test)]
+mod testutil;
+mod tick_schedule;
+mod timer;
+mod vrf;
+pub use crate::core::{CompactSummary, Core, DiffCursor, DiffLimit, DiffMessage, LimitedDiff};
+pub use certificate::FinalityCertificate;
 pub use consensus::{Decider, DeciderConfig};
+pub use error::{Fatal, RejectReason, SnapshotError};
+pub use evidence::EquivocationEvidence;
+pub use executor::VirtualExecutor;
+pub use guard::{FileSigningGuard, MessageKind, SigningGuard};
+pub use leader::{LeaderSchedule, RoundRobin, WeightedRandom, WeightedRoundRobin};
+pub use msg::{Message, Proposal, Solicit, Vote};
+#[cfg(feature = "mutation-testing")]
+pub use mutation::Mutation;
+pub use params::ProtocolParams;
+pub use storage::{FileStorage, JournalEntry, Storage};
+pub use tick_schedule::{Adaptive, Constant, Exponential, Linear, TickSchedule};
+#[cfg(feature = "async-io")]
+pub use timer::AsyncIoTimer;
+#[cfg(feature = "tokio")]
+pub use timer::TokioTimer;
+pub use timer::{MockClock, Timer};
+pub use vrf::VrfElection;
@@ -5,6 +5,7 @@ use tap::Tap;
 use tmelcrypt::{Ed25519PK, HashVal};
 use tmelcrypt::{Ed25519SK, Hashable};
 
+/// Functionality common to every signed message.
 pub trait Message {
     fn chash(&self) -> HashVal;
     fn source(&self) -> Ed25519PK;
@@ -21,21 +22,33 @@ pub struct Proposal {
     pub tick: u64,
     pub body: Bytes,
     pub source: Ed25519PK,
+    /// Proof that the source may lead this tick, if leaders are secretly elected. See [crate::VrfElection].
+    pub vrf_proof: Bytes,
     pub signature: Bytes,
 }
 
 impl Proposal {
     /// Creates a new proposal.
     pub fn new(nonce: u128, tick: u64, body: Bytes, my_sk: Ed25519SK) -> Self {
-        let mut template = Proposal {
+        Self::unsigned(nonce, tick, body, my_sk.to_public()).signed(my_sk)
+    }
+
+    /// Creates a proposal without a signature, so that its hash is known before it is signed.
+    pub(crate) fn unsigned(nonce: u128, tick: u64, body: Bytes, source: Ed25519PK) -> Self {
+        Proposal {
             nonce,
             tick,
             body,
-            source: my_sk.to_public(),
+            source,
+            vrf_proof: Bytes::new(),
             signature: Bytes::new(),
-        };
-        template.signature = my_sk.sign(&template.chash()).into();
-        template
+        }
+    }
+
+    /// Signs the proposal.
+    pub(crate) fn signed(mut self, my_sk: Ed25519SK) -> Self {
+        self.signature = my_sk.sign(&self.chash()).into();
+        self
     }
 }
 
@@ -63,21 +76,33 @@ pub struct Solicit {
     pub tick: u64,
     pub previous: HashVal,
     pub source: Ed25519PK,
+    /// Proof that the source may lead this tick, if leaders are secretly elected. See [crate::VrfElection].
+    pub vrf_proof: Bytes,
     pub signature: Bytes,
 }
 
 impl Solicit {
     /// Creates a new solicit.
     pub fn new(nonce: u128, tick: u64, previous: HashVal, my_sk: Ed25519SK) -> Self {
-        let mut template = Self {
+        Self::unsigned(nonce, tick, previous, my_sk.to_public()).signed(my_sk)
+    }
+
+    /// Creates a solicit without a signature, so that its hash is known before it is signed.
+    pub(crate) fn unsigned(nonce: u128, tick: u64, previous: HashVal, source: Ed25519PK) -> Self {
+        Self {
             nonce,
             tick,
             previous,
-            source: my_sk.to_public(),
+            source,
+            vrf_proof: Bytes::new(),
             signature: Bytes::new(),
-        };
-        template.signature = my_sk.sign(&template.chash()).into();
-        template
+        }
+    }
+
+    /// Signs the solicit.
+    pub(crate) fn signed(mut self, my_sk: Ed25519SK) -> Self {
+        self.signature = my_sk.sign(&self.chash()).into();
+        self
     }
 }
 
@@ -110,14 +135,23 @@ pub struct Vote {
 impl Vote {
     /// Creates a new vote.
     pub fn new(nonce: u128, voting_for: HashVal, my_sk: Ed25519SK) -> Self {
-        let mut template = Vote {
+        Self::unsigned(nonce, voting_for, my_sk.to_public()).signed(my_sk)
+    }
+
+    /// Creates a vote without a signature, so that its hash is known before it is signed.
+    pub(crate) fn unsigned(nonce: u128, voting_for: HashVal, source: Ed25519PK) -> Self {
+        Vote {
             nonce,
             voting_for,
-            source: my_sk.to_public(),
+            source,
             signature: Bytes::new(),
-        };
-        template.signature = my_sk.sign(&template.chash()).into();
-        template
+        }
+    }
+
+    /// Signs the vote.
+    pub(crate) fn signed(mut self, my_sk: Ed25519SK) -> Self {
+        self.signature = my_sk.sign(&self.chash()).into();
+        self
     }
 }
 
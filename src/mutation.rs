@@ -0,0 +1,24 @@
+/// A known-bad variant of the consensus logic, injected into every [crate::Core] of a Decider through [crate::DeciderConfig::mutation]. Each one voids the safety guarantees of Streamlet, so that a fuzzer can be checked to actually notice when the protocol isn't followed.
+///
+/// Only available with the `mutation-testing` feature. Never use it outside of tests.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Mutation {
+    /// A quorum is more than 1/2 of the vote weight, rather than more than 2/3.
+    WeakThreshold,
+    /// Two notarized messages with consecutive ticks finalize a chain, rather than three.
+    TwoTickFinalization,
+    /// We vote for every solicit we see, not only those extending the tip of a longest notarized chain.
+    VoteOffLncTips,
+    /// We accept, and vote for, a second proposal or solicit from the same leader for the same tick, rather than treating it as equivocation.
+    SkipTickSourceCheck,
+}
+
+impl Mutation {
+    /// Every mutation there is.
+    pub const ALL: [Mutation; 4] = [
+        Mutation::WeakThreshold,
+        Mutation::TwoTickFinalization,
+        Mutation::VoteOffLncTips,
+        Mutation::SkipTickSourceCheck,
+    ];
+}
@@ -0,0 +1,140 @@
+use serde::{Deserialize, Serialize};
+
+/// Tunable parameters of the protocol. The defaults are those of Streamlet: a quorum is more than 2/3 of the vote weight, and three notarized messages with consecutive ticks finalize a chain.
+///
+/// Other values void Streamlet's safety and liveness proofs. They are mostly useful for experiments, and for checking that a fuzzer notices when safety breaks.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProtocolParams {
+    /// A message is notarized once votes for it carry strictly more than `threshold_numerator / threshold_denominator` of the total vote weight.
+    pub threshold_numerator: u32,
+    /// See [ProtocolParams::threshold_numerator].
+    pub threshold_denominator: u32,
+    /// How many notarized messages with consecutive ticks it takes to finalize the chain up to them.
+    pub depth: usize,
+}
+
+impl Default for ProtocolParams {
+    fn default() -> Self {
+        Self {
+            threshold_numerator: 2,
+            threshold_denominator: 3,
+            depth: 3,
+        }
+    }
+}
+
+impl ProtocolParams {
+    /// Checks that the parameters make any sense at all: the threshold must be a fraction below 1, so that a quorum is possible, and the depth must be at least 1.
+    ///
+    /// Panics otherwise.
+    pub(crate) fn assert_valid(&self) {
+        assert!(
+            self.threshold_denominator > 0,
+            "threshold denominator must not be zero"
+        );
+        assert!(
+            self.threshold_numerator < self.threshold_denominator,
+            "a threshold of {}/{} can never be exceeded",
+            self.threshold_numerator,
+            self.threshold_denominator
+        );
+        assert!(self.depth > 0, "finalization depth must be at least 1");
+    }
+
+    /// Whether the given vote weight is a quorum of the total vote weight.
+    pub(crate) fn is_quorum(&self, weight: u128, total_votes: u128) -> bool {
+        exceeds_fraction(
+            weight,
+            total_votes,
+            self.threshold_numerator,
+            self.threshold_denominator,
+        )
+    }
+}
+
+/// Whether `weight / total > numerator / denominator`, computed exactly without overflowing.
+pub(crate) fn exceeds_fraction(
+    weight: u128,
+    total: u128,
+    numerator: u32,
+    denominator: u32,
+) -> bool {
+    mul_wide(weight, denominator) > mul_wide(total, numerator)
+}
+
+/// Multiplies without overflowing, returning the high and low halves of the 256-bit product.
+fn mul_wide(a: u128, b: u32) -> (u128, u128) {
+    let lo = (a as u64 as u128) * b as u128;
+    let hi = (a >> 64) * b as u128 + (lo >> 64);
+    (hi >> 64, (hi << 64) | (lo as u64 as u128))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exact_fractions() {
+        for _ in 0..100000 {
+            // small enough for the naive computation not to overflow
+            let total = fastrand::u128(..1 << 96);
+            let weight = fastrand::u128(..=total);
+            let (num, den) = (fastrand::u32(..), fastrand::u32(1..));
+            assert_eq!(
+                exceeds_fraction(weight, total, num, den),
+                weight * den as u128 > total * num as u128
+            );
+        }
+        let params = ProtocolParams::default();
+        assert!(params.is_quorum(u128::MAX, u128::MAX));
+        assert!(params.is_quorum(u128::MAX / 3 * 2 + 1, u128::MAX));
+        assert!(!params.is_quorum(u128::MAX / 3 * 2, u128::MAX));
+        assert!(!params.is_quorum(0, 0));
+        assert!(exceeds_fraction(
+            u128::MAX,
+            u128::MAX,
+            u32::MAX - 1,
+            u32::MAX
+        ));
+        assert!(!exceeds_fraction(
+            u128::MAX - 1,
+            u128::MAX,
+            u32::MAX,
+            u32::MAX
+        ));
+    }
+
+    fn core_with(params: ProtocolParams) -> crate::Core {
+        let pk = tmelcrypt::Ed25519SK::generate().to_public();
+        crate::Core::new(0, [(pk, 1)], move |_| pk, |_| true, params)
+    }
+
+    #[test]
+    #[should_panic(expected = "threshold denominator must not be zero")]
+    fn zero_denominator() {
+        core_with(ProtocolParams {
+            threshold_numerator: 0,
+            threshold_denominator: 0,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "can never be exceeded")]
+    fn unreachable_threshold() {
+        core_with(ProtocolParams {
+            threshold_numerator: 3,
+            threshold_denominator: 3,
+            ..Default::default()
+        });
+    }
+
+    #[test]
+    #[should_panic(expected = "finalization depth must be at least 1")]
+    fn zero_depth() {
+        core_with(ProtocolParams {
+            depth: 0,
+            ..Default::default()
+        });
+    }
+}
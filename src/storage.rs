@@ -0,0 +1,244 @@
+use std::{
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+use stdcode::StdcodeSerializeExt;
+
+use crate::core::DiffMessage;
+
+/// An entry in the journal kept in a [Storage].
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum JournalEntry {
+    /// A message that was signed or accepted by the [crate::Core].
+    Message(DiffMessage),
+    /// The [crate::Decider] moved on to the given tick.
+    Tick(u64),
+}
+
+/// Durable storage for the write-ahead log of a [crate::Decider]. See [crate::Decider::recover].
+pub trait Storage: Send + Sync + 'static {
+    /// Appends an entry to the journal. Must not return until the entry would survive a crash.
+    fn append(&self, entry: &JournalEntry) -> std::io::Result<()>;
+
+    /// Reads back every entry in the journal, in the order they were appended.
+    fn replay(&self) -> std::io::Result<Vec<JournalEntry>>;
+}
+
+/// A [Storage] backed by an append-only file. Every entry is a big-endian `u32` length, followed by that many bytes of stdcode-encoded [JournalEntry].
+///
+/// A record torn by a crash in the middle of an append is discarded when the file is opened. A complete record that fails to decode is corruption rather than a crash, and the file then refuses to open, since discarding everything after it could make us sign again for ticks we already signed for.
+pub struct FileStorage {
+    file: Mutex<File>,
+    /// Whether a failed append left a partial record behind that we couldn't remove, so that anything appended after it would be lost.
+    poisoned: AtomicBool,
+}
+
+impl FileStorage {
+    /// Opens the journal at the given path, creating it if it doesn't exist.
+    pub fn open(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let (_, good_len) = read_entries(&mut file)?;
+        if good_len < file.metadata()?.len() {
+            log::warn!("discarding torn journal record at offset {}", good_len);
+            file.set_len(good_len)?;
+            file.sync_all()?;
+        }
+        Ok(Self {
+            file: Mutex::new(file),
+            poisoned: AtomicBool::new(false),
+        })
+    }
+}
+
+impl Storage for FileStorage {
+    fn append(&self, entry: &JournalEntry) -> std::io::Result<()> {
+        let entry = entry.stdcode();
+        let mut record = Vec::with_capacity(entry.len() + 4);
+        record.extend_from_slice(&(entry.len() as u32).to_be_bytes());
+        record.extend_from_slice(&entry);
+        let mut file = self.file.lock().unwrap();
+        if self.poisoned.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other(
+                "an earlier append left a partial record in the journal",
+            ));
+        }
+        let len = file.metadata()?.len();
+        let written = file.write_all(&record).and_then(|_| file.sync_data());
+        if written.is_err() && file.set_len(len).and_then(|_| file.sync_all()).is_err() {
+            self.poisoned.store(true, Ordering::SeqCst);
+        }
+        written
+    }
+
+    fn replay(&self) -> std::io::Result<Vec<JournalEntry>> {
+        let mut file = self.file.lock().unwrap();
+        Ok(read_entries(&mut file)?.0)
+    }
+}
+
+/// Reads all complete entries from the start of the file, returning them along with the length of the file they take up. Fails if a complete entry doesn't decode.
+fn read_entries(file: &mut File) -> std::io::Result<(Vec<JournalEntry>, u64)> {
+    file.seek(SeekFrom::Start(0))?;
+    let mut buf = vec![];
+    file.read_to_end(&mut buf)?;
+    let mut entries = vec![];
+    let mut offset = 0;
+    while buf.len() >= offset + 4 {
+        let len = u32::from_be_bytes(buf[offset..offset + 4].try_into().unwrap()) as usize;
+        let record = if let Some(record) = buf.get(offset + 4..offset + 4 + len) {
+            record
+        } else {
+            break;
+        };
+        let entry = stdcode::deserialize(record).map_err(|err| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("corrupted journal record at offset {}: {}", offset, err),
+            )
+        })?;
+        entries.push(entry);
+        offset += 4 + len;
+    }
+    Ok((entries, offset as u64))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        testutil::{temp_path, Lonely},
+        Decider,
+    };
+
+    #[test]
+    fn torn_tail() {
+        let path = temp_path("wal");
+        let storage = FileStorage::open(&path).unwrap();
+        storage.append(&JournalEntry::Tick(1)).unwrap();
+        storage.append(&JournalEntry::Tick(2)).unwrap();
+        drop(storage);
+        // a crash halfway through writing the third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&[0, 0, 0, 9, 1]).unwrap();
+        drop(file);
+
+        let storage = FileStorage::open(&path).unwrap();
+        storage.append(&JournalEntry::Tick(3)).unwrap();
+        let ticks = storage
+            .replay()
+            .unwrap()
+            .into_iter()
+            .map(|entry| match entry {
+                JournalEntry::Tick(tick) => tick,
+                JournalEntry::Message(_) => panic!("unexpected message"),
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(ticks, vec![1, 2, 3]);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_middle() {
+        let path = temp_path("wal");
+        let storage = FileStorage::open(&path).unwrap();
+        for tick in 1..=3 {
+            storage.append(&JournalEntry::Tick(tick)).unwrap();
+        }
+        drop(storage);
+        // scribble over the body of the second record
+        let mut buf = std::fs::read(&path).unwrap();
+        let first = 4 + u32::from_be_bytes(buf[..4].try_into().unwrap()) as usize;
+        let second = u32::from_be_bytes(buf[first..first + 4].try_into().unwrap()) as usize;
+        buf[first + 4..first + 4 + second].fill(0xff);
+        std::fs::write(&path, &buf).unwrap();
+
+        let err = FileStorage::open(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        // and nothing was thrown away
+        assert_eq!(std::fs::read(&path).unwrap(), buf);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recover_without_equivocating() {
+        let path = temp_path("wal");
+        let lonely = Lonely::new();
+        let generated = lonely.generated.clone();
+        let config = || Lonely {
+            sk: lonely.sk,
+            generated: generated.clone(),
+            ..Lonely::new()
+        };
+
+        // crash right after proposing at tick 0
+        let mut decider = Decider::recover(config(), FileStorage::open(&path).unwrap()).unwrap();
+        assert_eq!(decider.tick().unwrap(), None);
+        drop(decider);
+
+        // crash again after voting at tick 0, then soliciting and voting at tick 1
+        let mut decider = Decider::recover(config(), FileStorage::open(&path).unwrap()).unwrap();
+        for _ in 0..3 {
+            assert_eq!(decider.tick().unwrap(), None);
+        }
+        drop(decider);
+
+        let mut decider = Decider::recover(config(), FileStorage::open(&path).unwrap()).unwrap();
+        let decision = loop {
+            if let Some(decision) = decider.tick().unwrap() {
+                break decision;
+            }
+        };
+        assert_eq!(*generated.lock().unwrap(), vec![decision]);
+        assert!(decider.equivocation_evidence().is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recover_after_import() {
+        let path = temp_path("wal");
+        let lonely = Lonely::new();
+        let sk = lonely.sk;
+        let mut archived = Decider::new(lonely);
+        let decision = loop {
+            if let Some(decision) = archived.tick().unwrap() {
+                break decision;
+            }
+        };
+
+        // the snapshot is well past the tick we're at
+        let mut decider = Decider::recover(
+            Lonely {
+                sk,
+                ..Lonely::new()
+            },
+            FileStorage::open(&path).unwrap(),
+        )
+        .unwrap();
+        decider
+            .import_snapshot(&archived.export_snapshot())
+            .unwrap();
+        drop(decider);
+
+        let mut decider = Decider::recover(
+            Lonely {
+                sk,
+                ..Lonely::new()
+            },
+            FileStorage::open(&path).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(decider.tick().unwrap(), Some(decision));
+        std::fs::remove_file(path).unwrap();
+    }
+}
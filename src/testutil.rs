@@ -0,0 +1,147 @@
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use tmelcrypt::{Ed25519PK, Ed25519SK};
+
+use crate::{Constant, Core, DeciderConfig, JournalEntry, MockClock, Storage, TickSchedule, Timer};
+
+/// A fresh path in the temporary directory, with the given extension.
+pub fn temp_path(extension: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("streamlette-{}.{}", fastrand::u64(..), extension))
+}
+
+/// A lone player, which decides all by itself. It proposes something different every time it's asked to, and remembers what.
+pub struct Lonely {
+    pub sk: Ed25519SK,
+    pub generated: Arc<Mutex<Vec<Bytes>>>,
+    /// How long every tick takes, if not the default.
+    pub interval: Option<Duration>,
+    /// The clock to use, if not the default, or a [MockClock] nobody advances without a timer backend.
+    pub timer: Option<Arc<dyn Timer>>,
+}
+
+impl Lonely {
+    /// Creates a lone player with a fresh key and default timing.
+    pub fn new() -> Self {
+        Self {
+            sk: Ed25519SK::generate(),
+            generated: Default::default(),
+            interval: None,
+            timer: None,
+        }
+    }
+}
+
+#[async_trait]
+impl DeciderConfig for Lonely {
+    fn generate_proposal(&self) -> Bytes {
+        let prop = Bytes::from(fastrand::u64(..).to_be_bytes().to_vec());
+        self.generated.lock().unwrap().push(prop.clone());
+        prop
+    }
+
+    fn verify_proposal(&self, _prop: &[u8]) -> bool {
+        true
+    }
+
+    async fn sync_core(&self, _core: &mut Core) {
+        futures_lite::future::pending().await
+    }
+
+    fn vote_weights(&self) -> BTreeMap<Ed25519PK, u64> {
+        std::iter::once((self.sk.to_public(), 1)).collect()
+    }
+
+    fn seed(&self) -> u128 {
+        0
+    }
+
+    fn my_secret(&self) -> Ed25519SK {
+        self.sk
+    }
+
+    fn tick_schedule(&self) -> Box<dyn TickSchedule> {
+        match self.interval {
+            Some(interval) => Box::new(Constant(interval)),
+            None => Box::<crate::Exponential>::default(),
+        }
+    }
+
+    fn timer(&self) -> Arc<dyn Timer> {
+        self.timer.clone().unwrap_or_else(|| {
+            #[cfg(any(feature = "async-io", feature = "tokio"))]
+            return crate::timer::default_timer();
+            #[cfg(not(any(feature = "async-io", feature = "tokio")))]
+            return Arc::new(MockClock::new());
+        })
+    }
+}
+
+/// Players that sync by looking at each other's cores every 100 milliseconds.
+pub struct Player {
+    pub keys: Vec<Ed25519SK>,
+    pub index: usize,
+    pub cores: Arc<Mutex<BTreeMap<usize, Core>>>,
+    pub clock: MockClock,
+}
+
+#[async_trait]
+impl DeciderConfig for Player {
+    fn generate_proposal(&self) -> Bytes {
+        Bytes::from(format!("from {}", self.index))
+    }
+
+    fn verify_proposal(&self, _prop: &[u8]) -> bool {
+        true
+    }
+
+    async fn sync_core(&self, core: &mut Core) {
+        loop {
+            let others = self.cores.lock().unwrap().clone();
+            for other in others.values() {
+                for msg in other.get_diff(&core.summary()) {
+                    let _ = core.apply_one_diff(msg);
+                }
+            }
+            self.cores.lock().unwrap().insert(self.index, core.clone());
+            self.clock.sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    fn vote_weights(&self) -> BTreeMap<Ed25519PK, u64> {
+        self.keys.iter().map(|sk| (sk.to_public(), 1)).collect()
+    }
+
+    fn seed(&self) -> u128 {
+        0
+    }
+
+    fn my_secret(&self) -> Ed25519SK {
+        self.keys[self.index]
+    }
+
+    fn timer(&self) -> Arc<dyn Timer> {
+        Arc::new(self.clock.clone())
+    }
+}
+
+/// A journal kept in memory. Clones share the same journal.
+#[derive(Clone, Default)]
+pub struct MemoryStorage(pub Arc<Mutex<Vec<JournalEntry>>>);
+
+impl Storage for MemoryStorage {
+    fn append(&self, entry: &JournalEntry) -> std::io::Result<()> {
+        self.0.lock().unwrap().push(entry.clone());
+        Ok(())
+    }
+
+    fn replay(&self) -> std::io::Result<Vec<JournalEntry>> {
+        Ok(self.0.lock().unwrap().clone())
+    }
+}
@@ -0,0 +1,226 @@
+use std::time::Duration;
+
+/// Decides how long each tick lasts in [crate::Decider::tick_to_end]. A fresh schedule is obtained from [crate::DeciderConfig::tick_schedule] every time `tick_to_end` is called, and asked for one interval per tick, in order.
+///
+/// All players should use schedules with similar timing, or the slow ones won't be able to keep up.
+pub trait TickSchedule: Send + 'static {
+    /// Returns how long the next tick should last.
+    fn next_interval(&mut self) -> Duration;
+
+    /// Called at the end of every tick with how long after we voted something from that tick was seen to be notarized, or None if nothing was by the end of the tick. Does nothing by default.
+    fn observe(&mut self, notarized_after: Option<Duration>) {
+        let _ = notarized_after;
+    }
+}
+
+/// Every tick lasts the same amount of time. Good for networks with predictable latency, like LANs.
+pub struct Constant(pub Duration);
+
+impl TickSchedule for Constant {
+    fn next_interval(&mut self) -> Duration {
+        self.0
+    }
+}
+
+/// Every tick lasts a constant factor longer than the last one, up to a maximum. The default schedule starts at one second and grows by 5% each tick without bound.
+pub struct Exponential {
+    current: Duration,
+    factor: f64,
+    max: Duration,
+}
+
+impl Exponential {
+    /// Creates a schedule starting at `initial`, multiplied by `factor` every tick, and never more than `max`.
+    pub fn new(initial: Duration, factor: f64, max: Duration) -> Self {
+        Self {
+            current: initial.min(max),
+            factor,
+            max,
+        }
+    }
+}
+
+impl Default for Exponential {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1), 1.05, Duration::MAX)
+    }
+}
+
+impl TickSchedule for Exponential {
+    fn next_interval(&mut self) -> Duration {
+        let toret = self.current;
+        self.current = Duration::try_from_secs_f64(self.current.as_secs_f64() * self.factor)
+            .unwrap_or(self.max)
+            .min(self.max);
+        toret
+    }
+}
+
+/// Every tick lasts a constant amount of time longer than the last one, up to a maximum.
+pub struct Linear {
+    current: Duration,
+    step: Duration,
+    max: Duration,
+}
+
+impl Linear {
+    /// Creates a schedule starting at `initial`, growing by `step` every tick, and never more than `max`.
+    pub fn new(initial: Duration, step: Duration, max: Duration) -> Self {
+        Self {
+            current: initial.min(max),
+            step,
+            max,
+        }
+    }
+}
+
+impl TickSchedule for Linear {
+    fn next_interval(&mut self) -> Duration {
+        let toret = self.current;
+        self.current = self.current.saturating_add(self.step).min(self.max);
+        toret
+    }
+}
+
+/// Adapts to how long notarization actually takes. Ticks last a multiple of a moving average of the observed notarization latency, and back off exponentially when nothing gets notarized, always staying between a minimum and a maximum.
+pub struct Adaptive {
+    current: Duration,
+    average: Option<Duration>,
+    min: Duration,
+    max: Duration,
+}
+
+impl Adaptive {
+    /// How many times the average latency each tick lasts.
+    const MULTIPLIER: u32 = 4;
+    /// How much to back off by when a tick fails to notarize anything.
+    const BACKOFF: u32 = 2;
+
+    /// Creates a schedule starting at `initial` and staying between `min` and `max`.
+    pub fn new(initial: Duration, min: Duration, max: Duration) -> Self {
+        Self {
+            current: initial.clamp(min, max),
+            average: None,
+            min,
+            max,
+        }
+    }
+}
+
+impl TickSchedule for Adaptive {
+    fn next_interval(&mut self) -> Duration {
+        self.current
+    }
+
+    fn observe(&mut self, notarized_after: Option<Duration>) {
+        let next = if let Some(latency) = notarized_after {
+            // exponentially weighted, with the latest observation counting for a quarter
+            let average = self
+                .average
+                .map(|avg| (avg * 3 + latency) / 4)
+                .unwrap_or(latency);
+            self.average = Some(average);
+            average.saturating_mul(Self::MULTIPLIER)
+        } else {
+            self.current.saturating_mul(Self::BACKOFF)
+        };
+        self.current = next.clamp(self.min, self.max);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use futures_lite::future;
+
+    use super::*;
+    use crate::{testutil::Lonely, Decider, MockClock};
+
+    fn intervals(schedule: &mut dyn TickSchedule, n: usize) -> Vec<Duration> {
+        (0..n).map(|_| schedule.next_interval()).collect()
+    }
+
+    #[test]
+    fn fixed_schedules() {
+        let ms = Duration::from_millis;
+        assert_eq!(intervals(&mut Constant(ms(100)), 3), vec![ms(100); 3]);
+        assert_eq!(
+            intervals(&mut Exponential::new(ms(100), 2.0, ms(500)), 5),
+            vec![ms(100), ms(200), ms(400), ms(500), ms(500)]
+        );
+        assert_eq!(
+            intervals(&mut Linear::new(ms(100), ms(150), ms(500)), 5),
+            vec![ms(100), ms(250), ms(400), ms(500), ms(500)]
+        );
+        // the default never overflows
+        let mut default = Exponential::default();
+        assert_eq!(default.next_interval(), Duration::from_secs(1));
+        assert!(intervals(&mut default, 5000)
+            .windows(2)
+            .all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn adaptive_schedule() {
+        let ms = Duration::from_millis;
+        let mut schedule = Adaptive::new(ms(1000), ms(50), ms(10000));
+        for _ in 0..50 {
+            schedule.next_interval();
+            schedule.observe(Some(ms(20)));
+        }
+        assert_eq!(schedule.next_interval(), ms(80));
+        // backs off when things stop getting notarized
+        schedule.observe(None);
+        schedule.observe(None);
+        assert_eq!(schedule.next_interval(), ms(320));
+        for _ in 0..20 {
+            schedule.observe(None);
+        }
+        assert_eq!(schedule.next_interval(), ms(10000));
+        // never goes below the minimum
+        for _ in 0..50 {
+            schedule.observe(Some(ms(1)));
+        }
+        assert_eq!(schedule.next_interval(), ms(50));
+    }
+
+    #[cfg(feature = "async-io")]
+    #[test]
+    fn tick_to_end_follows_schedule() {
+        let start = std::time::Instant::now();
+        let hasty = Lonely {
+            interval: Some(Duration::from_millis(10)),
+            // smol runs the async-io reactor, and nothing else
+            timer: Some(Arc::new(crate::AsyncIoTimer)),
+            ..Lonely::new()
+        };
+        let generated = hasty.generated.clone();
+        let mut decider = Decider::new(hasty);
+        let decision = smol::block_on(decider.tick_to_end()).unwrap();
+        assert_eq!(*generated.lock().unwrap(), vec![decision]);
+        // with the default schedule, this would take several seconds
+        assert!(start.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn tiny_intervals_on_mock_clock() {
+        let clock = MockClock::new();
+        for nanos in [0, 1, 7, 15] {
+            let tiny = Lonely {
+                interval: Some(Duration::from_nanos(nanos)),
+                timer: Some(Arc::new(clock.clone())),
+                ..Lonely::new()
+            };
+            let mut decider = Decider::new(tiny);
+            let mut decision = Box::pin(decider.tick_to_end());
+            // each poll runs until the decider waits on the clock, which must therefore move on
+            let mut polls = 0;
+            while future::block_on(future::poll_once(&mut decision)).is_none() {
+                clock.advance(clock.next_wakeup().expect("stuck without a timer"));
+                polls += 1;
+                assert!(polls < 1000, "stuck at {} nanoseconds a tick", nanos);
+            }
+        }
+    }
+}
@@ -0,0 +1,256 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use futures_lite::future::Boxed;
+
+/// A source of time for the [crate::Decider], so that it isn't tied to any particular async runtime. Returned by [crate::DeciderConfig::timer].
+///
+/// Backends for async-io (the default) and tokio are available through the features of the same names, and [MockClock] is a clock that only moves when told to.
+pub trait Timer: Send + Sync + 'static {
+    /// Returns the current time.
+    fn now(&self) -> Instant;
+
+    /// Returns a future that resolves once the given amount of time has passed.
+    fn sleep(&self, duration: Duration) -> Boxed<()>;
+}
+
+impl<T: Timer + ?Sized> Timer for Arc<T> {
+    fn now(&self) -> Instant {
+        self.as_ref().now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        self.as_ref().sleep(duration)
+    }
+}
+
+/// The timer to use when [crate::DeciderConfig::timer] isn't overridden. Prefers async-io, which works under any executor, when both backends are enabled.
+#[cfg(any(feature = "async-io", feature = "tokio"))]
+pub(crate) fn default_timer() -> Arc<dyn Timer> {
+    #[cfg(feature = "async-io")]
+    return Arc::new(AsyncIoTimer);
+    #[cfg(not(feature = "async-io"))]
+    return Arc::new(TokioTimer);
+}
+
+/// A [Timer] using the async-io reactor, as used by smol. Works under any executor.
+#[cfg(feature = "async-io")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncIoTimer;
+
+#[cfg(feature = "async-io")]
+impl Timer for AsyncIoTimer {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        Box::pin(async move {
+            async_io::Timer::after(duration).await;
+        })
+    }
+}
+
+/// A [Timer] using tokio's time driver. Must be used within a tokio runtime with time enabled, and follows tokio's clock when it's paused.
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioTimer;
+
+#[cfg(feature = "tokio")]
+impl Timer for TokioTimer {
+    fn now(&self) -> Instant {
+        tokio::time::Instant::now().into_std()
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        Box::pin(tokio::time::sleep(duration))
+    }
+}
+
+/// A [Timer] whose time only moves forward when [MockClock::advance] is called, for driving Deciders deterministically in tests and simulations. Clones share the same clock.
+#[derive(Clone)]
+pub struct MockClock {
+    inner: Arc<Mutex<MockInner>>,
+}
+
+struct MockInner {
+    start: Instant,
+    elapsed: Duration,
+    /// Wakers of pending sleeps, keyed by deadline and a unique id.
+    sleepers: BTreeMap<(Duration, u64), Waker>,
+    next_id: u64,
+}
+
+impl MockClock {
+    /// Creates a clock, starting at the current time.
+    pub fn new() -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(MockInner {
+                start: Instant::now(),
+                elapsed: Duration::ZERO,
+                sleepers: BTreeMap::new(),
+                next_id: 0,
+            })),
+        }
+    }
+
+    /// Returns how much time has passed on this clock since it was created.
+    pub fn elapsed(&self) -> Duration {
+        self.inner.lock().unwrap().elapsed
+    }
+
+    /// Moves time forward, waking up every sleep that has finished.
+    pub fn advance(&self, duration: Duration) {
+        let woken = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.elapsed += duration;
+            let first_pending = (inner.elapsed + Duration::from_nanos(1), 0);
+            let pending = inner.sleepers.split_off(&first_pending);
+            std::mem::replace(&mut inner.sleepers, pending)
+        };
+        // wake outside the lock, since waking may poll
+        woken.into_values().for_each(Waker::wake);
+    }
+
+    /// Returns how long until the earliest pending sleep finishes, if there is one.
+    pub fn next_wakeup(&self) -> Option<Duration> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .sleepers
+            .keys()
+            .next()
+            .map(|(deadline, _)| deadline.saturating_sub(inner.elapsed))
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Timer for MockClock {
+    fn now(&self) -> Instant {
+        let inner = self.inner.lock().unwrap();
+        inner.start + inner.elapsed
+    }
+
+    fn sleep(&self, duration: Duration) -> Boxed<()> {
+        let mut inner = self.inner.lock().unwrap();
+        let id = inner.next_id;
+        inner.next_id += 1;
+        Box::pin(MockSleep {
+            clock: self.inner.clone(),
+            deadline: inner.elapsed + duration,
+            id,
+        })
+    }
+}
+
+struct MockSleep {
+    clock: Arc<Mutex<MockInner>>,
+    deadline: Duration,
+    id: u64,
+}
+
+impl Future for MockSleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let mut inner = self.clock.lock().unwrap();
+        if inner.elapsed >= self.deadline {
+            Poll::Ready(())
+        } else {
+            inner
+                .sleepers
+                .insert((self.deadline, self.id), cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for MockSleep {
+    fn drop(&mut self) {
+        if let Ok(mut inner) = self.clock.lock() {
+            inner.sleepers.remove(&(self.deadline, self.id));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::future;
+
+    use super::*;
+    use crate::{testutil::Lonely, Decider};
+
+    #[test]
+    fn mock_clock() {
+        let clock = MockClock::new();
+        let start = clock.now();
+        let mut short = clock.sleep(Duration::from_secs(1));
+        let mut long = clock.sleep(Duration::from_secs(5));
+        assert!(future::block_on(future::poll_once(&mut short)).is_none());
+        assert!(future::block_on(future::poll_once(&mut long)).is_none());
+        assert_eq!(clock.next_wakeup(), Some(Duration::from_secs(1)));
+
+        clock.advance(Duration::from_secs(1));
+        assert!(future::block_on(future::poll_once(&mut short)).is_some());
+        assert!(future::block_on(future::poll_once(&mut long)).is_none());
+        assert_eq!(clock.next_wakeup(), Some(Duration::from_secs(4)));
+        assert_eq!(clock.now() - start, Duration::from_secs(1));
+
+        drop(long);
+        assert_eq!(clock.next_wakeup(), None);
+        assert_eq!(clock.elapsed(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn decider_on_mock_clock() {
+        let clock = MockClock::new();
+        let lonely = Lonely {
+            interval: Some(Duration::from_secs(60)),
+            timer: Some(Arc::new(clock.clone())),
+            ..Lonely::new()
+        };
+        let generated = lonely.generated.clone();
+        let mut decider = Decider::new(lonely);
+        let mut decision = Box::pin(decider.tick_to_end());
+        let decision = loop {
+            if let Some(decision) = future::block_on(future::poll_once(&mut decision)) {
+                break decision.unwrap();
+            }
+            clock.advance(clock.next_wakeup().expect("stuck without a timer"));
+        };
+        assert_eq!(*generated.lock().unwrap(), vec![decision]);
+        // a few minutes passed on the clock, but not in reality
+        assert!(clock.elapsed() >= Duration::from_secs(120));
+    }
+
+    #[cfg(feature = "tokio")]
+    #[test]
+    fn decider_on_tokio() {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap();
+        let mut decider = Decider::new(Lonely {
+            timer: Some(Arc::new(TokioTimer)),
+            ..Lonely::new()
+        });
+        let start = Instant::now();
+        runtime.block_on(async {
+            tokio::time::timeout(Duration::from_millis(100), decider.sync_state(None))
+                .await
+                .unwrap_err();
+            decider.sync_state(Some(Duration::from_millis(10))).await;
+        });
+        assert!(start.elapsed() >= Duration::from_millis(110));
+    }
+}
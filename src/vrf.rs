@@ -0,0 +1,232 @@
+use curve25519_dalek_ng::{
+    constants::ED25519_BASEPOINT_POINT,
+    edwards::{CompressedEdwardsY, EdwardsPoint},
+    scalar::Scalar,
+};
+use sha2::{Digest, Sha512};
+use stdcode::StdcodeSerializeExt;
+use tmelcrypt::{Ed25519PK, Ed25519SK};
+
+/// Secret leader election through a verifiable random function (VRF).
+///
+/// Every tick, each player privately evaluates a VRF over the instance nonce and the tick with its secret key. If the output falls below a threshold proportional to the player's weight, the player may lead that tick, proving so by attaching the VRF proof to its [crate::Proposal] or [crate::Solicit]. Nobody else can tell who the leaders of a tick are before they speak up, so they can't be targeted in advance.
+///
+/// Several players, or none at all, may be eligible to lead any given tick. Honest players vote for at most one proposal or solicit per tick, preferring the one with the lowest VRF output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VrfElection {
+    /// The expected number of eligible leaders per tick. Higher values make ticks without any leader rarer, at the cost of more competing messages. Must be at least 1, or creating the [crate::Decider] panics.
+    pub expected_leaders: u64,
+}
+
+impl Default for VrfElection {
+    fn default() -> Self {
+        Self {
+            expected_leaders: 2,
+        }
+    }
+}
+
+impl VrfElection {
+    /// Checks that some player can ever be eligible, which takes at least 1 expected leader.
+    ///
+    /// Panics otherwise.
+    pub(crate) fn assert_valid(&self) {
+        assert!(
+            self.expected_leaders > 0,
+            "expected leaders per tick must be at least 1"
+        );
+    }
+
+    /// Whether a player with the given weight out of the total is eligible to lead, given its VRF output. That is, whether `output / 2^64 < expected_leaders * weight / total_weight`.
+    pub(crate) fn is_eligible(&self, output: u64, weight: u64, total_weight: u128) -> bool {
+        let expected = match (weight as u128).checked_mul(self.expected_leaders as u128) {
+            Some(expected) if expected < total_weight => expected,
+            // certainly eligible, unless there's no weight at all
+            _ => return weight > 0,
+        };
+        // scale everything down so that the total fits in 64 bits, losing a negligible amount of precision
+        let shift = (128 - total_weight.leading_zeros()).saturating_sub(64);
+        let threshold = ((expected >> shift) << 64) / (total_weight >> shift);
+        (output as u128) < threshold
+    }
+
+    /// The VRF input for the given instance and tick.
+    pub(crate) fn alpha(nonce: u128, tick: u64) -> Vec<u8> {
+        (nonce, tick).stdcode()
+    }
+}
+
+/// The ciphersuite of ECVRF-EDWARDS25519-SHA512-TAI, from RFC 9381.
+const SUITE: u8 = 0x03;
+
+/// Evaluates the VRF on the given input, returning the proof and the output, which is the first 8 bytes of the RFC 9381 "beta" interpreted as a big-endian integer.
+pub(crate) fn prove(sk: Ed25519SK, alpha: &[u8]) -> (Vec<u8>, u64) {
+    let (x, nonce_key) = expand_secret(&sk);
+    let pk = sk.to_public();
+    let h = encode_to_curve(&pk, alpha);
+    let gamma = x * h;
+    let k = Scalar::from_hash(
+        Sha512::new()
+            .chain(nonce_key)
+            .chain(h.compress().as_bytes()),
+    );
+    let c = challenge(&pk, &h, &gamma, &(k * ED25519_BASEPOINT_POINT), &(k * h));
+    let s = k + c * x;
+    let mut proof = Vec::with_capacity(80);
+    proof.extend_from_slice(gamma.compress().as_bytes());
+    proof.extend_from_slice(&c.as_bytes()[..16]);
+    proof.extend_from_slice(s.as_bytes());
+    (proof, output_of(&gamma))
+}
+
+/// Verifies a VRF proof for the given public key and input, returning the output if it's valid.
+pub(crate) fn verify(pk: &Ed25519PK, alpha: &[u8], proof: &[u8]) -> Option<u64> {
+    if proof.len() != 80 {
+        return None;
+    }
+    let y = CompressedEdwardsY(pk.0).decompress()?;
+    if y.is_small_order() {
+        return None;
+    }
+    let gamma = CompressedEdwardsY(proof[..32].try_into().unwrap()).decompress()?;
+    let mut c = [0u8; 32];
+    c[..16].copy_from_slice(&proof[32..48]);
+    let c = Scalar::from_bits(c);
+    let s = Scalar::from_canonical_bytes(proof[48..].try_into().unwrap())?;
+    let h = encode_to_curve(pk, alpha);
+    let u = EdwardsPoint::vartime_double_scalar_mul_basepoint(&-c, &y, &s);
+    let v = s * h - c * gamma;
+    if challenge(pk, &h, &gamma, &u, &v) == c {
+        Some(output_of(&gamma))
+    } else {
+        None
+    }
+}
+
+/// Derives the secret scalar and the nonce-generation key from an ed25519 secret key, exactly like ed25519 signing does, so that the VRF public key is the ed25519 public key.
+fn expand_secret(sk: &Ed25519SK) -> (Scalar, [u8; 32]) {
+    let digest = Sha512::digest(&sk.0[..32]);
+    let mut x = [0u8; 32];
+    x.copy_from_slice(&digest[..32]);
+    x[0] &= 248;
+    x[31] &= 127;
+    x[31] |= 64;
+    (Scalar::from_bits(x), digest[32..].try_into().unwrap())
+}
+
+/// The try-and-increment hash to curve of RFC 9381.
+fn encode_to_curve(pk: &Ed25519PK, alpha: &[u8]) -> EdwardsPoint {
+    for ctr in 0..=u8::MAX {
+        let digest = Sha512::new()
+            .chain([SUITE, 0x01])
+            .chain(pk.0)
+            .chain(alpha)
+            .chain([ctr, 0x00])
+            .finalize();
+        if let Some(point) = CompressedEdwardsY(digest[..32].try_into().unwrap()).decompress() {
+            return point.mul_by_cofactor();
+        }
+    }
+    // happens with probability 2^-256
+    unreachable!("could not hash to curve")
+}
+
+fn challenge(
+    pk: &Ed25519PK,
+    h: &EdwardsPoint,
+    gamma: &EdwardsPoint,
+    u: &EdwardsPoint,
+    v: &EdwardsPoint,
+) -> Scalar {
+    let digest = Sha512::new()
+        .chain([SUITE, 0x02])
+        .chain(pk.0)
+        .chain(h.compress().as_bytes())
+        .chain(gamma.compress().as_bytes())
+        .chain(u.compress().as_bytes())
+        .chain(v.compress().as_bytes())
+        .chain([0x00])
+        .finalize();
+    let mut c = [0u8; 32];
+    c[..16].copy_from_slice(&digest[..16]);
+    Scalar::from_bits(c)
+}
+
+/// The start of the RFC 9381 "beta" for the given gamma.
+fn output_of(gamma: &EdwardsPoint) -> u64 {
+    let beta = Sha512::new()
+        .chain([SUITE, 0x03])
+        .chain(gamma.mul_by_cofactor().compress().as_bytes())
+        .chain([0x00])
+        .finalize();
+    u64::from_be_bytes(beta[..8].try_into().unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rfc9381_test_vector() {
+        let seed = hex::decode("9d61b19deffd5a60ba844af492ec2cc44449c5697b326919703bac031cae7f60")
+            .unwrap();
+        let pk = hex::decode("d75a980182b10ab7d54bfed3c964073a0ee172f3daa62325af021a68f707511a")
+            .unwrap();
+        let mut sk = [0u8; 64];
+        sk[..32].copy_from_slice(&seed);
+        sk[32..].copy_from_slice(&pk);
+        let sk = Ed25519SK(sk);
+        assert_eq!(sk.to_public().0[..], pk[..]);
+        let (proof, output) = prove(sk, b"");
+        assert_eq!(hex::encode(&proof), "8657106690b5526245a92b003bb079ccd1a92130477671f6fc01ad16f26f723f26f8a57ccaed74ee1b190bed1f479d9727d2d0f9b005a6e456a35d4fb0daab1268a1b0db10836d9826a528ca76567805");
+        assert_eq!(verify(&sk.to_public(), b"", &proof), Some(output));
+        assert_eq!(output, 0x90cf1df3b703cce5);
+    }
+
+    #[test]
+    fn bad_proofs_rejected() {
+        let sk = Ed25519SK::generate();
+        let (proof, output) = prove(sk, b"hello");
+        assert_eq!(verify(&sk.to_public(), b"hello", &proof), Some(output));
+        assert_eq!(verify(&sk.to_public(), b"world", &proof), None);
+        assert_eq!(
+            verify(&Ed25519SK::generate().to_public(), b"hello", &proof),
+            None
+        );
+        for i in 0..proof.len() {
+            let mut tampered = proof.clone();
+            tampered[i] ^= 1;
+            assert_eq!(verify(&sk.to_public(), b"hello", &tampered), None);
+        }
+        assert_eq!(verify(&sk.to_public(), b"hello", &proof[1..]), None);
+    }
+
+    #[test]
+    fn eligibility_with_huge_weights() {
+        let election = VrfElection {
+            expected_leaders: 2,
+        };
+        let total = u64::MAX as u128 * 10;
+        let eligible = (0..100000)
+            .filter(|_| election.is_eligible(fastrand::u64(..), u64::MAX, total))
+            .count();
+        assert!((19000..21000).contains(&eligible), "{}", eligible);
+        for _ in 0..1000 {
+            let total = fastrand::u128(..);
+            assert!(!election.is_eligible(fastrand::u64(..), 0, total));
+            assert!(election.is_eligible(u64::MAX, u64::MAX, u64::MAX as u128));
+            assert!(!election.is_eligible(u64::MAX, 1, total.max(2)));
+        }
+        assert!(!election.is_eligible(0, 0, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "expected leaders per tick must be at least 1")]
+    fn no_expected_leaders() {
+        let pk = Ed25519SK::generate().to_public();
+        let mut core = crate::Core::new(0, [(pk, 1)], move |_| pk, |_| true, Default::default());
+        core.set_vrf_election(VrfElection {
+            expected_leaders: 0,
+        });
+    }
+}